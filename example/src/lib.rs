@@ -1,14 +1,16 @@
 extern crate proxy_wasm;
 
-use lazy_static::*;
-use log::info;
+use log::{error, info};
 use proxy_wasm::context::*;
 use proxy_wasm::envoy_log::*;
 use proxy_wasm::payload::*;
+use proxy_wasm::queue::*;
 use proxy_wasm::reply::*;
+use proxy_wasm::service::*;
 use proxy_wasm::types::*;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 // =============== RootContext ============================
 struct SampleRootContext {}
@@ -40,6 +42,12 @@ impl Context for SampleContext {
   }
 
   fn on_request_headers(&self, _headers: u32) -> FilterHeadersStatus {
+    // Report the requested path to the stats aggregator service.
+    if let Ok(queue_id) = resolve_shared_queue("stats", "request_paths") {
+      if let Ok(path) = get_request_header(":path".to_string()) {
+        enqueue_shared_queue(queue_id, path.to_string().as_bytes());
+      }
+    }
     // {
     //   let header = get_request_header_pairs();
     //   for (k, v) in header.unwrap().iter() {
@@ -48,11 +56,13 @@ impl Context for SampleContext {
     // }
     {
       let mut h = HashMap::new();
-      let header = get_request_header_pairs().unwrap();
       h.insert(":path".to_string(), "/value".to_string());
       h.insert(":method".to_string(), "GET".to_string());
       h.insert("Host".to_string(), "example.com".to_string());
       set_request_header_pairs(&h);
+      // The route was selected before this filter ran, so it has to be picked again for the
+      // rewritten :path to take effect.
+      clear_route_cache();
       let header = get_request_header_pairs().unwrap();
       for (k, v) in header.iter() {
         info!("{} {}", k, v);
@@ -76,19 +86,81 @@ impl ContextFactory for SampleContextFactory {
 }
 // ========================================================
 
-lazy_static! {
-  static ref SAMPLE_ROOT_CONTEXT_FACTORY: Box<dyn RootContextFactory + Sync + Send> =
-    Box::new(SampleRootContextFactory {});
-  static ref SAMPLE_CONTEXT_FACTORY: Box<dyn ContextFactory + Sync + Send> =
-    Box::new(SampleContextFactory {});
+// ================= Authn Context ========================
+struct AuthnContext {}
+
+impl Context for AuthnContext {
+  fn on_create(&self) {}
+
+  fn on_request_headers(&self, _headers: u32) -> FilterHeadersStatus {
+    match get_request_header("authorization".to_string()) {
+      Ok(v) if !v.to_string().is_empty() => FilterHeadersStatus::Continue,
+      _ => {
+        LocalReply::unauthorized()
+          .header("www-authenticate", "Bearer")
+          .details("authn_missing_credentials")
+          .send();
+        FilterHeadersStatus::StopIteration
+      }
+    }
+  }
+}
+// ========================================================
+
+// ============= Stats Aggregator Service ==================
+struct StatsAggregator {
+  request_counts: Mutex<HashMap<String, u64>>,
+}
+
+impl ServiceContext for StatsAggregator {
+  fn on_start(&self, _vm_configuration: Option<Vec<u8>>) -> bool {
+    if let Err(e) = register_shared_queue("request_paths") {
+      error!("failed to register queue: {}", e);
+      return false;
+    }
+    set_tick_period(Duration::from_secs(10));
+    true
+  }
+
+  fn on_queue_ready(&self, queue_id: u32) {
+    let mut request_counts = self.request_counts.lock().unwrap();
+    while let Ok(Some(path)) = dequeue_shared_queue(queue_id) {
+      *request_counts
+        .entry(String::from_utf8_lossy(&path).into_owned())
+        .or_insert(0) += 1;
+    }
+  }
+
+  fn on_tick(&self) {
+    for (path, count) in self.request_counts.lock().unwrap().iter() {
+      info!("{}: {} requests", path, count);
+    }
+  }
 }
+// ========================================================
 
 #[no_mangle]
 fn _start() {
   Logger::init().unwrap();
+  // Each filter is selected by the root_id of its plugin configuration in envoy.yaml.
   register_factory(
     "my_root_id",
-    &SAMPLE_CONTEXT_FACTORY,
-    &SAMPLE_ROOT_CONTEXT_FACTORY,
+    SampleContextFactory {},
+    SampleRootContextFactory {},
+  );
+  register_factory(
+    "authn",
+    |_root_context: Arc<dyn RootContext + Sync + Send>| -> Arc<dyn Context + Sync + Send> {
+      Arc::new(AuthnContext {})
+    },
+    || -> Arc<dyn RootContext + Sync + Send> { Arc::new(NoopRootContext {}) },
+  );
+  register_service_factory(
+    "stats_aggregator",
+    || -> Arc<dyn ServiceContext + Sync + Send> {
+      Arc::new(StatsAggregator {
+        request_counts: Mutex::new(HashMap::new()),
+      })
+    },
   );
 }
@@ -0,0 +1,354 @@
+use crate::context::*;
+use crate::encoding::json_escape;
+use crate::payload::*;
+use crate::payload_wrapper::get_header_map_pairs;
+use crate::service::read_configuration;
+use crate::time::format_rfc3339;
+use crate::types::*;
+use log::warn;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Plugin loaded as an access logger, e.g. through `envoy.access_loggers.wasm`. It only sees
+/// completed streams.
+pub trait AccessLogger {
+  fn on_configure(&self, _configuration: Option<Vec<u8>>) -> bool {
+    true
+  }
+  fn on_log(&self, _entry: &AccessLogEntry);
+}
+
+pub trait AccessLoggerFactory {
+  fn create(&self) -> Arc<dyn AccessLogger + Sync + Send>;
+}
+
+impl<F> AccessLoggerFactory for F
+where
+  F: Fn() -> Arc<dyn AccessLogger + Sync + Send>,
+{
+  fn create(&self) -> Arc<dyn AccessLogger + Sync + Send> {
+    self()
+  }
+}
+
+struct AccessLoggerRootContext {
+  logger: Arc<dyn AccessLogger + Sync + Send>,
+}
+
+impl RootContext for AccessLoggerRootContext {
+  fn on_configure(&self, _configuration_size: u32) -> bool {
+    let configuration = read_configuration(_configuration_size, get_plugin_configuration);
+    self.logger.on_configure(configuration)
+  }
+
+  fn on_log(&self) {
+    self.logger.on_log(&AccessLogEntry::load())
+  }
+}
+
+/// Registers an access logger under `root_id`.
+pub fn register_access_logger<L>(root_id: &str, factory: L)
+where
+  L: AccessLoggerFactory + Sync + Send + 'static,
+{
+  register_factory(
+    root_id,
+    |_root_context: Arc<dyn RootContext + Sync + Send>| -> Arc<dyn Context + Sync + Send> {
+      Arc::new(NoopContext {})
+    },
+    move || -> Arc<dyn RootContext + Sync + Send> {
+      Arc::new(AccessLoggerRootContext {
+        logger: factory.create(),
+      })
+    },
+  );
+}
+
+// Short names of the Envoy response flags, by bit.
+const RESPONSE_FLAGS: [&str; 23] = [
+  "LH", "UH", "UT", "LR", "UR", "UF", "UC", "UO", "NR", "DI", "FI", "RL", "UAEX", "RLSE", "DC",
+  "URX", "SI", "IH", "DPE", "UMSDR", "RFCF", "NFCF", "DT",
+];
+
+fn property_string(path: &[&str]) -> Option<String> {
+  match get_property(path) {
+    Ok(Some(value)) if !value.is_empty() => Some(String::from_utf8_lossy(&value).to_string()),
+    Ok(_) => None,
+    Err(e) => {
+      warn!("failed to read property {}: {}", path.join("."), e);
+      None
+    }
+  }
+}
+
+// Integers, timestamps and durations are serialized by the host as 64-bit little endian.
+fn property_u64(path: &[&str]) -> Option<u64> {
+  match get_property(path) {
+    Ok(Some(value)) => <[u8; 8]>::try_from(value.as_slice())
+      .ok()
+      .map(u64::from_le_bytes),
+    Ok(None) => None,
+    Err(e) => {
+      warn!("failed to read property {}: {}", path.join("."), e);
+      None
+    }
+  }
+}
+
+/// Snapshot of a completed stream, read from headers and host properties.
+#[derive(Clone, Debug, Default)]
+pub struct AccessLogEntry {
+  pub request_headers: HashMap<String, String>,
+  pub request_trailers: HashMap<String, String>,
+  pub response_headers: HashMap<String, String>,
+  pub response_trailers: HashMap<String, String>,
+  pub response_code: Option<u32>,
+  pub response_code_details: Option<String>,
+  pub response_flags: u64,
+  pub protocol: Option<String>,
+  pub start_time: Option<SystemTime>,
+  pub duration: Option<Duration>,
+  /// Body bytes received from downstream.
+  pub bytes_received: Option<u64>,
+  /// Body bytes sent to downstream.
+  pub bytes_sent: Option<u64>,
+  pub downstream_address: Option<String>,
+  pub upstream_address: Option<String>,
+  pub upstream_cluster: Option<String>,
+}
+
+impl AccessLogEntry {
+  /// Reads the stream being logged. Only meaningful from `on_log`.
+  pub fn load() -> AccessLogEntry {
+    let headers = |htype| get_header_map_pairs(htype).unwrap_or_default();
+    AccessLogEntry {
+      request_headers: headers(HeaderMapType::RequestHeaders),
+      request_trailers: headers(HeaderMapType::RequestTrailers),
+      response_headers: headers(HeaderMapType::ResponseHeaders),
+      response_trailers: headers(HeaderMapType::ResponseTrailers),
+      response_code: property_u64(&["response", "code"]).map(|c| c as u32),
+      response_code_details: property_string(&["response", "code_details"]),
+      response_flags: property_u64(&["response", "flags"]).unwrap_or(0),
+      protocol: property_string(&["request", "protocol"]),
+      start_time: property_u64(&["request", "time"]).map(|t| UNIX_EPOCH + Duration::from_nanos(t)),
+      duration: property_u64(&["request", "duration"]).map(Duration::from_nanos),
+      bytes_received: property_u64(&["request", "size"]),
+      bytes_sent: property_u64(&["response", "size"]),
+      downstream_address: property_string(&["source", "address"]),
+      upstream_address: property_string(&["upstream", "address"]),
+      upstream_cluster: property_string(&["cluster_name"])
+        .or_else(|| property_string(&["xds", "cluster_name"])),
+    }
+  }
+
+  pub fn request_header(&self, key: &str) -> Option<&str> {
+    self.request_headers.get(key).map(|v| v.as_str())
+  }
+
+  pub fn response_header(&self, key: &str) -> Option<&str> {
+    self.response_headers.get(key).map(|v| v.as_str())
+  }
+
+  /// Path before any rewrite by the route.
+  pub fn original_path(&self) -> Option<&str> {
+    self
+      .request_header("x-envoy-original-path")
+      .or_else(|| self.request_header(":path"))
+  }
+
+  /// Response flags in Envoy notation, e.g. `UH,UF`, or `-` if none is set.
+  pub fn response_flags_str(&self) -> String {
+    let flags: Vec<&str> = RESPONSE_FLAGS
+      .iter()
+      .enumerate()
+      .filter(|(bit, _)| self.response_flags & (1 << bit) != 0)
+      .map(|(_, name)| *name)
+      .collect();
+    match flags.is_empty() {
+      true => "-".to_string(),
+      false => flags.join(","),
+    }
+  }
+
+  /// Formats the entry like Envoy's default access log format.
+  pub fn format_default(&self) -> String {
+    let or_dash = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+    let header = |v: Option<&str>| v.unwrap_or("-").to_string();
+    format!(
+      "[{}] \"{} {} {}\" {} {} {} {} {} {} \"{}\" \"{}\" \"{}\" \"{}\" \"{}\"",
+      or_dash(self.start_time.map(format_rfc3339)),
+      header(self.request_header(":method")),
+      header(self.original_path()),
+      or_dash(self.protocol.clone()),
+      or_dash(self.response_code.map(|c| c.to_string())),
+      self.response_flags_str(),
+      self.bytes_received.unwrap_or(0),
+      self.bytes_sent.unwrap_or(0),
+      or_dash(self.duration.map(|d| d.as_millis().to_string())),
+      header(self.response_header("x-envoy-upstream-service-time")),
+      header(self.request_header("x-forwarded-for")),
+      header(self.request_header("user-agent")),
+      header(self.request_header("x-request-id")),
+      header(self.request_header(":authority")),
+      or_dash(self.upstream_address.clone()),
+    )
+  }
+
+  /// Formats the entry as one JSON object, with the fields of the default format. Missing
+  /// values are `null`.
+  pub fn format_json(&self) -> String {
+    let string = |v: Option<&str>| match v {
+      Some(v) => format!("\"{}\"", json_escape(v)),
+      None => "null".to_string(),
+    };
+    let number = |v: Option<String>| v.unwrap_or_else(|| "null".to_string());
+    let fields = [
+      (
+        "start_time",
+        string(self.start_time.map(format_rfc3339).as_deref()),
+      ),
+      ("method", string(self.request_header(":method"))),
+      ("path", string(self.original_path())),
+      ("protocol", string(self.protocol.as_deref())),
+      (
+        "response_code",
+        number(self.response_code.map(|c| c.to_string())),
+      ),
+      (
+        "response_code_details",
+        string(self.response_code_details.as_deref()),
+      ),
+      ("response_flags", string(Some(&self.response_flags_str()))),
+      (
+        "bytes_received",
+        number(self.bytes_received.map(|b| b.to_string())),
+      ),
+      ("bytes_sent", number(self.bytes_sent.map(|b| b.to_string()))),
+      (
+        "duration",
+        number(self.duration.map(|d| d.as_millis().to_string())),
+      ),
+      (
+        "upstream_service_time",
+        string(self.response_header("x-envoy-upstream-service-time")),
+      ),
+      (
+        "x_forwarded_for",
+        string(self.request_header("x-forwarded-for")),
+      ),
+      ("user_agent", string(self.request_header("user-agent"))),
+      ("request_id", string(self.request_header("x-request-id"))),
+      ("authority", string(self.request_header(":authority"))),
+      (
+        "downstream_address",
+        string(self.downstream_address.as_deref()),
+      ),
+      ("upstream_host", string(self.upstream_address.as_deref())),
+      ("upstream_cluster", string(self.upstream_cluster.as_deref())),
+    ];
+    let fields: Vec<String> = fields
+      .iter()
+      .map(|(k, v)| format!("\"{}\":{}", k, v))
+      .collect();
+    format!("{{{}}}", fields.join(","))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn headers(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    pairs
+      .iter()
+      .map(|(k, v)| (k.to_string(), v.to_string()))
+      .collect()
+  }
+
+  fn entry() -> AccessLogEntry {
+    AccessLogEntry {
+      request_headers: headers(&[
+        (":method", "GET"),
+        (":path", "/rewritten"),
+        ("x-envoy-original-path", "/search?q=\"a\""),
+        (":authority", "example.com"),
+        ("user-agent", "curl/7.0"),
+        ("x-request-id", "abc"),
+      ]),
+      response_headers: headers(&[("x-envoy-upstream-service-time", "12")]),
+      response_code: Some(503),
+      response_code_details: Some("upstream\\reset".to_string()),
+      response_flags: 0b10_0010,
+      protocol: Some("HTTP/1.1".to_string()),
+      start_time: Some(UNIX_EPOCH + Duration::from_millis(1_500)),
+      duration: Some(Duration::from_micros(42_700)),
+      bytes_received: Some(10),
+      bytes_sent: Some(20),
+      downstream_address: Some("10.0.0.1:5000".to_string()),
+      upstream_address: Some("10.0.0.2:80".to_string()),
+      upstream_cluster: Some("backend".to_string()),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn formats_response_flags() {
+    let flags = [
+      (0, "-"),
+      (1, "LH"),
+      (0b10_0010, "UH,UF"),
+      (1 << 12 | 1 << 22, "UAEX,DT"),
+      // Bits without a known flag are ignored.
+      (1 << 40, "-"),
+    ];
+    for (response_flags, expected) in flags.iter() {
+      let entry = AccessLogEntry {
+        response_flags: *response_flags,
+        ..Default::default()
+      };
+      assert_eq!(entry.response_flags_str(), *expected);
+    }
+  }
+
+  #[test]
+  fn formats_default_line() {
+    assert_eq!(
+      entry().format_default(),
+      "[1970-01-01T00:00:01.500Z] \"GET /search?q=\"a\" HTTP/1.1\" 503 UH,UF 10 20 42 12 \"-\" \
+       \"curl/7.0\" \"abc\" \"example.com\" \"10.0.0.2:80\""
+    );
+    assert_eq!(
+      AccessLogEntry::default().format_default(),
+      "[-] \"- - -\" - - 0 0 - - \"-\" \"-\" \"-\" \"-\" \"-\""
+    );
+  }
+
+  #[test]
+  fn formats_json_object() {
+    assert_eq!(
+      entry().format_json(),
+      concat!(
+        r#"{"start_time":"1970-01-01T00:00:01.500Z","method":"GET","path":"/search?q=\"a\"","#,
+        r#""protocol":"HTTP/1.1","response_code":503,"#,
+        r#""response_code_details":"upstream\\reset","response_flags":"UH,UF","#,
+        r#""bytes_received":10,"bytes_sent":20,"duration":42,"upstream_service_time":"12","#,
+        r#""x_forwarded_for":null,"user_agent":"curl/7.0","request_id":"abc","#,
+        r#""authority":"example.com","downstream_address":"10.0.0.1:5000","#,
+        r#""upstream_host":"10.0.0.2:80","upstream_cluster":"backend"}"#
+      )
+    );
+    assert_eq!(
+      AccessLogEntry::default().format_json(),
+      concat!(
+        r#"{"start_time":null,"method":null,"path":null,"protocol":null,"#,
+        r#""response_code":null,"response_code_details":null,"response_flags":"-","#,
+        r#""bytes_received":null,"bytes_sent":null,"duration":null,"#,
+        r#""upstream_service_time":null,"x_forwarded_for":null,"user_agent":null,"#,
+        r#""request_id":null,"authority":null,"downstream_address":null,"#,
+        r#""upstream_host":null,"upstream_cluster":null}"#
+      )
+    );
+  }
+}
@@ -0,0 +1,237 @@
+use crate::executor::StreamDirection;
+use crate::payload::*;
+use crate::reply::LocalReply;
+use crate::types::*;
+use log::warn;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyPolicy {
+  /// Buffers the whole body, up to the given number of bytes. A larger request body is answered
+  /// with 413, a larger response body with 500.
+  Buffer(usize),
+  /// Hands every chunk over as it arrives.
+  Chunked,
+  /// Buffers up to the given number of bytes, with the host applying flow control meanwhile.
+  /// Once the limit is reached, the buffered data is handed over as a chunk and forwarded, so a
+  /// body larger than the buffer of the host doesn't stall the stream. The limit should stay
+  /// below the buffer limit of the listener or cluster.
+  Watermark(usize),
+}
+
+// What a body callback does, decided from the policy and the size of the buffered body.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BodyStep {
+  // Keeps buffering until the end of the stream.
+  Buffer,
+  // Keeps buffering with flow control.
+  Watermark,
+  // Hands the buffered data over and forwards it.
+  Process,
+  // The body exceeds the limit of `Buffer`.
+  Reject,
+}
+
+fn body_step(policy: BodyPolicy, body_buffer_length: usize, end_of_stream: bool) -> BodyStep {
+  match policy {
+    BodyPolicy::Buffer(limit) if body_buffer_length > limit => BodyStep::Reject,
+    BodyPolicy::Buffer(_) if !end_of_stream => BodyStep::Buffer,
+    BodyPolicy::Watermark(limit) if !end_of_stream && body_buffer_length < limit => {
+      BodyStep::Watermark
+    }
+    _ => BodyStep::Process,
+  }
+}
+
+// Whether the trailers callback still has buffered data to hand over.
+fn processes_trailers(policy: BodyPolicy) -> bool {
+  policy != BodyPolicy::Chunked
+}
+
+/// Drives `on_request_body` or `on_response_body` according to a `BodyPolicy`.
+///
+/// The body, or the chunk with `Chunked`, is passed to a callback which may return a replacement.
+/// A body whose size changes must not be sent with the original `content-length`, see
+/// `remove_content_length`.
+///
+/// A stream ending with trailers, such as any gRPC response, never delivers `end_of_stream` to a
+/// body callback. With `Buffer` and `Watermark`, call `on_trailers` from the trailers callback so
+/// the buffered body, or its remainder, is still handed to the callback.
+pub struct BodyHandler {
+  direction: StreamDirection,
+  policy: BodyPolicy,
+}
+
+impl BodyHandler {
+  pub fn new(direction: StreamDirection, policy: BodyPolicy) -> BodyHandler {
+    BodyHandler { direction, policy }
+  }
+
+  pub fn request(policy: BodyPolicy) -> BodyHandler {
+    BodyHandler::new(StreamDirection::Request, policy)
+  }
+
+  pub fn response(policy: BodyPolicy) -> BodyHandler {
+    BodyHandler::new(StreamDirection::Response, policy)
+  }
+
+  pub fn direction(&self) -> StreamDirection {
+    self.direction
+  }
+
+  pub fn policy(&self) -> BodyPolicy {
+    self.policy
+  }
+
+  fn buffer_type(&self) -> BufferType {
+    match self.direction {
+      StreamDirection::Request => BufferType::HttpRequestBody,
+      StreamDirection::Response => BufferType::HttpResponseBody,
+    }
+  }
+
+  /// Removes `content-length` from the headers of the direction. Call it from the headers
+  /// callback when the body may be replaced by one of another size.
+  pub fn remove_content_length(&self) -> WasmResult {
+    match self.direction {
+      StreamDirection::Request => remove_request_header("content-length".to_string()),
+      StreamDirection::Response => remove_response_header("content-length".to_string()),
+    }
+  }
+
+  /// Appends `data` to the body of the direction, e.g. from the trailers callback once every
+  /// chunk was handed over.
+  pub fn append(&self, data: &[u8]) -> WasmResult {
+    let length = match get_buffer_bytes(self.buffer_type(), 0, usize::MAX) {
+      Ok(body) => body.len(),
+      Err(e) => {
+        warn!("failed to read {:?} body: {}", self.direction, e);
+        return WasmResult::InternalFailure;
+      }
+    };
+    set_buffer_bytes(self.buffer_type(), length, 0, data)
+  }
+
+  /// Handles a body callback. `f` receives the body, or the current chunk, along with whether the
+  /// stream ends; it is not called until the whole body is buffered, unless the policy is
+  /// `Chunked`, or the `Watermark` limit is reached. Returning `Some` replaces the data given to
+  /// `f`.
+  pub fn on_body<F>(&self, body_buffer_length: usize, end_of_stream: bool, f: F) -> FilterDataStatus
+  where
+    F: FnOnce(&[u8], bool) -> Option<Vec<u8>>,
+  {
+    match body_step(self.policy, body_buffer_length, end_of_stream) {
+      BodyStep::Reject => {
+        self.reject(body_buffer_length);
+        FilterDataStatus::StopIterationNoBuffer
+      }
+      BodyStep::Buffer => FilterDataStatus::StopIterationAndBuffer,
+      BodyStep::Watermark => FilterDataStatus::StopIterationAndWatermark,
+      BodyStep::Process => {
+        self.process(body_buffer_length, end_of_stream, f);
+        FilterDataStatus::Continue
+      }
+    }
+  }
+
+  /// Handles a trailers callback. With `Buffer` and `Watermark`, `f` receives the data buffered
+  /// since the last body callback which continued, as in `on_body` at the end of the stream. With `Chunked`, every chunk was already handed
+  /// over and `f` is not called.
+  pub fn on_trailers<F>(&self, f: F) -> FilterTrailersStatus
+  where
+    F: FnOnce(&[u8], bool) -> Option<Vec<u8>>,
+  {
+    if processes_trailers(self.policy) {
+      self.process(usize::MAX, true, f);
+    }
+    FilterTrailersStatus::Continue
+  }
+
+  fn process<F>(&self, body_buffer_length: usize, end_of_stream: bool, f: F)
+  where
+    F: FnOnce(&[u8], bool) -> Option<Vec<u8>>,
+  {
+    let body = match body_buffer_length {
+      0 => Ok(Vec::new()),
+      _ => get_buffer_bytes(self.buffer_type(), 0, body_buffer_length),
+    };
+    let body = match body {
+      Ok(body) => body,
+      Err(e) => {
+        warn!("failed to read {:?} body: {}", self.direction, e);
+        return;
+      }
+    };
+    if let Some(replacement) = f(&body, end_of_stream) {
+      match set_buffer_bytes(self.buffer_type(), 0, body.len(), &replacement) {
+        WasmResult::Ok => {}
+        r => warn!("failed to replace {:?} body: {}", self.direction, r),
+      }
+    }
+  }
+
+  fn reject(&self, body_buffer_length: usize) {
+    let limit = match self.policy {
+      BodyPolicy::Buffer(limit) => limit,
+      _ => 0,
+    };
+    warn!(
+      "{:?} body of {} bytes exceeds the limit of {} bytes",
+      self.direction, body_buffer_length, limit
+    );
+    let reply = match self.direction {
+      StreamDirection::Request => LocalReply::payload_too_large(),
+      StreamDirection::Response => LocalReply::internal_server_error(),
+    };
+    match reply.details("body_too_large").send() {
+      WasmResult::Ok => {}
+      r => warn!("failed to send local reply: {}", r),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn buffer_waits_for_the_end_of_the_stream() {
+    let policy = BodyPolicy::Buffer(10);
+    assert_eq!(body_step(policy, 4, false), BodyStep::Buffer);
+    assert_eq!(body_step(policy, 10, false), BodyStep::Buffer);
+    assert_eq!(body_step(policy, 10, true), BodyStep::Process);
+    assert_eq!(body_step(policy, 0, true), BodyStep::Process);
+  }
+
+  #[test]
+  fn buffer_rejects_bodies_over_the_limit() {
+    let policy = BodyPolicy::Buffer(10);
+    assert_eq!(body_step(policy, 11, false), BodyStep::Reject);
+    assert_eq!(body_step(policy, 11, true), BodyStep::Reject);
+  }
+
+  #[test]
+  fn chunked_processes_every_chunk() {
+    assert_eq!(body_step(BodyPolicy::Chunked, 4, false), BodyStep::Process);
+    assert_eq!(body_step(BodyPolicy::Chunked, 0, true), BodyStep::Process);
+    assert_eq!(
+      body_step(BodyPolicy::Chunked, usize::MAX, false),
+      BodyStep::Process
+    );
+  }
+
+  #[test]
+  fn watermark_flushes_once_the_limit_is_reached() {
+    let policy = BodyPolicy::Watermark(10);
+    assert_eq!(body_step(policy, 9, false), BodyStep::Watermark);
+    assert_eq!(body_step(policy, 10, false), BodyStep::Process);
+    assert_eq!(body_step(policy, 1 << 30, false), BodyStep::Process);
+    assert_eq!(body_step(policy, 3, true), BodyStep::Process);
+  }
+
+  #[test]
+  fn trailers_flush_buffered_policies_only() {
+    assert!(processes_trailers(BodyPolicy::Buffer(10)));
+    assert!(processes_trailers(BodyPolicy::Watermark(10)));
+    assert!(!processes_trailers(BodyPolicy::Chunked));
+  }
+}
@@ -133,3 +133,73 @@ pub fn export_hashmap(_pairs: &HashMap<String, String>) -> (*mut c_char, usize)
     buffer_size,
   )
 }
+
+pub fn pairs_into_bytes(_pairs: &[(String, String)]) -> Vec<u8> {
+  if _pairs.is_empty() {
+    return Vec::new();
+  }
+  let mut buffer = Vec::<u8>::new();
+  // write length of pairs
+  buffer.extend_from_slice(&(_pairs.len() as u32).to_le_bytes());
+  // write length of keys and values
+  for (key, value) in _pairs {
+    buffer.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(&(value.len() as u32).to_le_bytes());
+  }
+  // write value of pairs
+  for (key, value) in _pairs {
+    buffer.extend_from_slice(key.as_bytes());
+    buffer.push(0);
+    buffer.extend_from_slice(value.as_bytes());
+    buffer.push(0);
+  }
+  buffer
+}
+
+/// Inverse of `pairs_into_bytes`, keeping the order and every value of repeated keys. Returns
+/// `None` for malformed buffers.
+pub fn bytes_into_pairs(buffer: &[u8]) -> Option<Vec<(String, String)>> {
+  let read_u32 = |offset: usize| -> Option<usize> {
+    let bytes = buffer.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+  };
+  if buffer.is_empty() {
+    return Some(Vec::new());
+  }
+  let count = read_u32(0)?;
+  let mut sizes = Vec::with_capacity(count);
+  for i in 0..count {
+    sizes.push((read_u32(4 + 8 * i)?, read_u32(8 + 8 * i)?));
+  }
+  let mut offset = 4 + 8 * count;
+  let mut read_string = |size: usize| -> Option<String> {
+    let bytes = buffer.get(offset..offset.checked_add(size)?)?;
+    offset += size + 1;
+    String::from_utf8(bytes.to_vec()).ok()
+  };
+  let mut pairs = Vec::with_capacity(count);
+  for (key_size, value_size) in sizes {
+    pairs.push((read_string(key_size)?, read_string(value_size)?));
+  }
+  Some(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn pairs_round_trip() {
+    let pairs = vec![
+      (":path".to_string(), "/".to_string()),
+      ("cookie".to_string(), "a=1".to_string()),
+      ("cookie".to_string(), "b=2".to_string()),
+      ("empty".to_string(), String::new()),
+    ];
+    let bytes = pairs_into_bytes(&pairs);
+    assert_eq!(bytes_into_pairs(&bytes), Some(pairs));
+    assert_eq!(bytes_into_pairs(&[]), Some(Vec::new()));
+    assert_eq!(bytes_into_pairs(&bytes[..bytes.len() - 3]), None);
+    assert_eq!(bytes_into_pairs(&[9, 0, 0, 0]), None);
+  }
+}
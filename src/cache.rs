@@ -0,0 +1,637 @@
+use crate::extensions::{insert_extension, remove_extension, with_extension};
+use crate::payload::*;
+use crate::reply::LocalReply;
+use crate::shared_data::{get_shared_data, set_shared_data, update_shared_data};
+use crate::time::current_time_nanos;
+use crate::types::*;
+use log::warn;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+// Headers which only apply to one connection, or must not be replayed to other clients.
+const UNCACHED_HEADERS: [&str; 9] = [
+  ":status",
+  "connection",
+  "keep-alive",
+  "transfer-encoding",
+  "te",
+  "trailer",
+  "upgrade",
+  "content-length",
+  "age",
+];
+
+/// Cached response, as stored in shared data.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheEntry {
+  pub status_code: u32,
+  pub headers: Vec<(String, String)>,
+  pub body: Vec<u8>,
+  /// Request headers listed in `vary`, with the values the response was stored for.
+  pub vary: Vec<(String, String)>,
+  pub stored_at: u64,
+  pub expires_at: u64,
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+  buffer.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+  buffer.extend_from_slice(bytes);
+}
+
+fn put_pairs(buffer: &mut Vec<u8>, pairs: &[(String, String)]) {
+  buffer.extend_from_slice(&(pairs.len() as u32).to_le_bytes());
+  for (key, value) in pairs {
+    put_bytes(buffer, key.as_bytes());
+    put_bytes(buffer, value.as_bytes());
+  }
+}
+
+struct Reader<'a> {
+  buffer: &'a [u8],
+  offset: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, size: usize) -> Option<&'a [u8]> {
+    let end = self.offset.checked_add(size)?;
+    let bytes = self.buffer.get(self.offset..end)?;
+    self.offset = end;
+    Some(bytes)
+  }
+
+  fn u32(&mut self) -> Option<u32> {
+    Some(u32::from_le_bytes(<[u8; 4]>::try_from(self.take(4)?).ok()?))
+  }
+
+  fn u64(&mut self) -> Option<u64> {
+    Some(u64::from_le_bytes(<[u8; 8]>::try_from(self.take(8)?).ok()?))
+  }
+
+  fn bytes(&mut self) -> Option<&'a [u8]> {
+    let size = self.u32()? as usize;
+    self.take(size)
+  }
+
+  fn string(&mut self) -> Option<String> {
+    String::from_utf8(self.bytes()?.to_vec()).ok()
+  }
+
+  fn pairs(&mut self) -> Option<Vec<(String, String)>> {
+    let count = self.u32()?;
+    let mut pairs = Vec::new();
+    for _ in 0..count {
+      pairs.push((self.string()?, self.string()?));
+    }
+    Some(pairs)
+  }
+}
+
+impl CacheEntry {
+  pub fn encode(&self) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(self.body.len() + 64);
+    buffer.extend_from_slice(&self.stored_at.to_le_bytes());
+    buffer.extend_from_slice(&self.expires_at.to_le_bytes());
+    buffer.extend_from_slice(&self.status_code.to_le_bytes());
+    put_pairs(&mut buffer, &self.headers);
+    put_pairs(&mut buffer, &self.vary);
+    put_bytes(&mut buffer, &self.body);
+    buffer
+  }
+
+  /// Returns `None` for evicted or malformed entries.
+  pub fn decode(buffer: &[u8]) -> Option<CacheEntry> {
+    let mut reader = Reader { buffer, offset: 0 };
+    Some(CacheEntry {
+      stored_at: reader.u64()?,
+      expires_at: reader.u64()?,
+      status_code: reader.u32()?,
+      headers: reader.pairs()?,
+      vary: reader.pairs()?,
+      body: reader.bytes()?.to_vec(),
+    })
+  }
+
+  pub fn is_fresh(&self, now: u64) -> bool {
+    now < self.expires_at
+  }
+
+  pub fn age(&self, now: u64) -> Duration {
+    Duration::from_nanos(now.saturating_sub(self.stored_at))
+  }
+}
+
+/// Parsed `cache-control` directives which matter to a shared cache.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheControl {
+  pub no_store: bool,
+  pub no_cache: bool,
+  pub private: bool,
+  pub public: bool,
+  pub must_revalidate: bool,
+  pub max_age: Option<u64>,
+  pub s_maxage: Option<u64>,
+}
+
+impl CacheControl {
+  pub fn parse(value: &str) -> CacheControl {
+    let mut cache_control = CacheControl::default();
+    for directive in value.split(',') {
+      let mut parts = directive.trim().splitn(2, '=');
+      let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+      let argument = parts.next().map(|a| a.trim().trim_matches('"'));
+      match name.as_str() {
+        "no-store" => cache_control.no_store = true,
+        "no-cache" => cache_control.no_cache = true,
+        "private" => cache_control.private = true,
+        "public" => cache_control.public = true,
+        "must-revalidate" => cache_control.must_revalidate = true,
+        "max-age" => cache_control.max_age = argument.and_then(|a| a.parse().ok()),
+        "s-maxage" => cache_control.s_maxage = argument.and_then(|a| a.parse().ok()),
+        _ => {}
+      }
+    }
+    cache_control
+  }
+}
+
+// Per-stream state, kept in the extensions between callbacks.
+struct CacheLookup {
+  key: String,
+  authorized: bool,
+}
+
+struct PendingEntry {
+  key: String,
+  entry: CacheEntry,
+  too_large: bool,
+}
+
+fn request_header(key: &str) -> Option<String> {
+  match get_request_header(key.to_string()).map(|v| v.to_string()) {
+    Ok(v) if !v.is_empty() => Some(v),
+    _ => None,
+  }
+}
+
+fn response_header(key: &str) -> Option<String> {
+  match get_response_header(key.to_string()).map(|v| v.to_string()) {
+    Ok(v) if !v.is_empty() => Some(v),
+    _ => None,
+  }
+}
+
+/// Shared cache of GET responses, stored in shared data so that all VMs with the same vm_id
+/// serve the same entries. The stream callbacks of a context call the matching methods.
+///
+/// Entries are keyed by scheme, authority and path. A response listing request headers in `vary` is
+/// only served to requests with the same values for them. Responses with trailers are not
+/// stored.
+///
+/// Responses to requests carrying `authorization` are only stored when they are explicitly
+/// shareable through `public`, `s-maxage` or `must-revalidate`, as required of shared caches by
+/// RFC 9111 section 3.5.
+pub struct ResponseCache {
+  name: String,
+  default_ttl: Duration,
+  max_entry_size: usize,
+  max_entries: usize,
+}
+
+impl ResponseCache {
+  /// `name` tells caches apart in shared data.
+  pub fn new(name: &str) -> ResponseCache {
+    ResponseCache {
+      name: name.to_string(),
+      default_ttl: Duration::from_secs(60),
+      max_entry_size: 1 << 20,
+      max_entries: 1024,
+    }
+  }
+
+  /// Lifetime of responses without `max-age`. Defaults to 60 seconds.
+  pub fn default_ttl(mut self, ttl: Duration) -> ResponseCache {
+    self.default_ttl = ttl;
+    self
+  }
+
+  /// Largest body stored. Defaults to 1 MiB.
+  pub fn max_entry_size(mut self, size: usize) -> ResponseCache {
+    self.max_entry_size = size;
+    self
+  }
+
+  /// Number of entries kept before the oldest ones are evicted. Defaults to 1024.
+  pub fn max_entries(mut self, entries: usize) -> ResponseCache {
+    self.max_entries = std::cmp::max(entries, 1);
+    self
+  }
+
+  fn index_key(&self) -> String {
+    format!("cache|{}|index", self.name)
+  }
+
+  /// Key of the entry for the current request, or `None` if it cannot be served from cache.
+  pub fn request_key(&self) -> Option<String> {
+    let method = request_header(":method")?;
+    if method != "GET" && method != "HEAD" {
+      return None;
+    }
+    let scheme = request_header(":scheme").unwrap_or_default();
+    let authority = request_header(":authority").unwrap_or_default();
+    let path = request_header(":path")?;
+    Some(self.entry_key(&scheme, &authority, &path))
+  }
+
+  fn entry_key(&self, scheme: &str, authority: &str, path: &str) -> String {
+    format!("cache|{}|{}|{}|{}", self.name, scheme, authority, path)
+  }
+
+  pub fn lookup(&self, key: &str) -> Option<CacheEntry> {
+    match get_shared_data(key) {
+      Ok((Some(value), _)) => CacheEntry::decode(&value),
+      Ok((None, _)) => None,
+      Err(e) => {
+        warn!("failed to read cache entry {}: {}", key, e);
+        None
+      }
+    }
+  }
+
+  /// Serves the request from cache if possible. Returns `StopIteration` when a cached response
+  /// was sent.
+  pub fn on_request_headers(&self) -> FilterHeadersStatus {
+    let key = match self.request_key() {
+      Some(key) => key,
+      None => return FilterHeadersStatus::Continue,
+    };
+    let cache_control = CacheControl::parse(&request_header("cache-control").unwrap_or_default());
+    if cache_control.no_store {
+      return FilterHeadersStatus::Continue;
+    }
+    if !cache_control.no_cache && cache_control.max_age != Some(0) {
+      if let Some(entry) = self.lookup(&key) {
+        let now = current_time_nanos();
+        let vary_matches = entry
+          .vary
+          .iter()
+          .all(|(name, value)| request_header(name).unwrap_or_default() == *value);
+        if entry.is_fresh(now) && vary_matches {
+          match self.serve(&entry, now) {
+            WasmResult::Ok => return FilterHeadersStatus::StopIteration,
+            r => warn!("failed to serve cache entry {}: {}", key, r),
+          }
+        }
+      }
+    }
+    if request_header(":method").as_deref() == Some("GET") {
+      let authorized = request_header("authorization").is_some();
+      insert_extension(CacheLookup { key, authorized });
+    }
+    FilterHeadersStatus::Continue
+  }
+
+  fn serve(&self, entry: &CacheEntry, now: u64) -> WasmResult {
+    let mut reply = LocalReply::new(entry.status_code).details("cache_hit");
+    if request_header(":method").as_deref() != Some("HEAD") {
+      reply = reply.body(entry.body.clone());
+    }
+    for (key, value) in &entry.headers {
+      reply = reply.header(key, value);
+    }
+    reply
+      .header("age", &entry.age(now).as_secs().to_string())
+      .header("x-cache", "HIT")
+      .send()
+  }
+
+  /// Decides whether the response is stored, from its status and headers.
+  pub fn on_response_headers(&self) -> FilterHeadersStatus {
+    let lookup = match remove_extension::<CacheLookup>() {
+      Some(lookup) => lookup,
+      None => return FilterHeadersStatus::Continue,
+    };
+    let status_code: u32 = match response_header(":status").and_then(|s| s.parse().ok()) {
+      Some(code) => code,
+      None => return FilterHeadersStatus::Continue,
+    };
+    let headers: Vec<(String, String)> = match get_response_header_pairs() {
+      Ok(headers) => headers.into_iter().collect(),
+      Err(_) => return FilterHeadersStatus::Continue,
+    };
+    let (ttl, vary_names) =
+      match storage_policy(status_code, &headers, lookup.authorized, self.default_ttl) {
+        Some(policy) => policy,
+        None => return FilterHeadersStatus::Continue,
+      };
+    let bodyless =
+      status_code == 204 || find_header(&headers, "content-length").as_deref() == Some("0");
+    let now = current_time_nanos();
+    let entry = CacheEntry {
+      status_code,
+      headers: headers
+        .into_iter()
+        .filter(|(k, _)| !UNCACHED_HEADERS.contains(&k.to_ascii_lowercase().as_str()))
+        .collect(),
+      body: Vec::new(),
+      vary: vary_names
+        .into_iter()
+        .map(|name| {
+          let value = request_header(&name).unwrap_or_default();
+          (name, value)
+        })
+        .collect(),
+      stored_at: now,
+      expires_at: now.saturating_add(ttl.as_nanos() as u64),
+    };
+    if bodyless {
+      self.store(&lookup.key, &entry);
+    } else {
+      insert_extension(PendingEntry {
+        key: lookup.key,
+        entry,
+        too_large: false,
+      });
+    }
+    FilterHeadersStatus::Continue
+  }
+
+  /// Copies the body of a response being stored. The body is forwarded as it arrives.
+  pub fn on_response_body(
+    &self,
+    body_buffer_length: usize,
+    end_of_stream: bool,
+  ) -> FilterDataStatus {
+    let max_entry_size = self.max_entry_size;
+    let tracked = with_extension(|pending: &mut PendingEntry| {
+      if pending.too_large {
+        return;
+      }
+      if pending.entry.body.len() + body_buffer_length > max_entry_size {
+        pending.too_large = true;
+        pending.entry.body = Vec::new();
+        return;
+      }
+      match get_buffer_bytes(BufferType::HttpResponseBody, 0, body_buffer_length) {
+        Ok(chunk) => pending.entry.body.extend(chunk),
+        Err(e) => {
+          warn!("failed to read response body: {}", e);
+          pending.too_large = true;
+        }
+      }
+    });
+    if tracked.is_some() && end_of_stream {
+      if let Some(pending) = remove_extension::<PendingEntry>() {
+        if !pending.too_large {
+          self.store(&pending.key, &pending.entry);
+        }
+      }
+    }
+    FilterDataStatus::Continue
+  }
+
+  /// Stores `entry` under `key`, evicting the oldest entries beyond `max_entries`.
+  pub fn store(&self, key: &str, entry: &CacheEntry) {
+    match set_shared_data(key, &entry.encode(), 0) {
+      WasmResult::Ok => {}
+      r => {
+        warn!("failed to store cache entry {}: {}", key, r);
+        return;
+      }
+    }
+    let max_entries = self.max_entries;
+    let evicted = update_shared_data(&self.index_key(), |index| {
+      update_index(index, key, max_entries)
+    });
+    match evicted {
+      // Shared data cannot be deleted, so evicted entries are emptied.
+      Ok(evicted) => {
+        for key in evicted {
+          self.evict(&key);
+        }
+      }
+      Err(e) => warn!("failed to update cache index: {}", e),
+    }
+  }
+
+  fn evict(&self, key: &str) {
+    match set_shared_data(key, &[], 0) {
+      WasmResult::Ok => {}
+      r => warn!("failed to evict cache entry {}: {}", key, r),
+    }
+  }
+
+  /// Evicts the entry of `scheme`, `authority` and `path`, e.g. after a write to the resource.
+  pub fn invalidate(&self, scheme: &str, authority: &str, path: &str) {
+    self.evict(&self.entry_key(scheme, authority, path));
+  }
+}
+
+fn find_header(headers: &[(String, String)], key: &str) -> Option<String> {
+  headers
+    .iter()
+    .find(|(k, _)| k.eq_ignore_ascii_case(key))
+    .map(|(_, v)| v.clone())
+}
+
+// Decides whether a response may be stored, and returns its lifetime and the lowercased names
+// of the request headers it varies on. `authorized` tells whether the request carried
+// `authorization`.
+fn storage_policy(
+  status_code: u32,
+  headers: &[(String, String)],
+  authorized: bool,
+  default_ttl: Duration,
+) -> Option<(Duration, Vec<String>)> {
+  if ![200, 203, 204, 300, 301, 308, 404, 410].contains(&status_code) {
+    return None;
+  }
+  let header = |key: &str| find_header(headers, key);
+  let cache_control = CacheControl::parse(&header("cache-control").unwrap_or_default());
+  if cache_control.no_store
+    || cache_control.no_cache
+    || cache_control.private
+    || header("set-cookie").is_some()
+  {
+    return None;
+  }
+  let shareable =
+    cache_control.public || cache_control.must_revalidate || cache_control.s_maxage.is_some();
+  if authorized && !shareable {
+    return None;
+  }
+  let vary_names: Vec<String> = header("vary")
+    .unwrap_or_default()
+    .split(',')
+    .map(|name| name.trim().to_ascii_lowercase())
+    .filter(|name| !name.is_empty())
+    .collect();
+  if vary_names.iter().any(|name| name == "*") {
+    return None;
+  }
+  let ttl = match cache_control.s_maxage.or(cache_control.max_age) {
+    Some(seconds) => Duration::from_secs(seconds),
+    None => default_ttl,
+  };
+  if ttl.as_nanos() == 0 {
+    return None;
+  }
+  Some((ttl, vary_names))
+}
+
+// Moves `key` to the end of the newline-separated index, and returns the new index along with
+// the oldest keys beyond `max_entries`.
+fn update_index(index: Option<&[u8]>, key: &str, max_entries: usize) -> (Vec<u8>, Vec<String>) {
+  let mut keys: Vec<&str> = index
+    .map(|index| std::str::from_utf8(index).unwrap_or(""))
+    .unwrap_or("")
+    .split('\n')
+    .filter(|k| !k.is_empty() && *k != key)
+    .collect();
+  keys.push(key);
+  let evicted: Vec<String> = match keys.len() > max_entries {
+    true => keys
+      .drain(..keys.len() - max_entries)
+      .map(|k| k.to_string())
+      .collect(),
+    false => Vec::new(),
+  };
+  (keys.join("\n").into_bytes(), evicted)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn entries_round_trip() {
+    let entry = CacheEntry {
+      status_code: 200,
+      headers: vec![
+        ("content-type".to_string(), "text/plain".to_string()),
+        ("link".to_string(), "</a>".to_string()),
+        ("link".to_string(), "</b>".to_string()),
+      ],
+      body: b"hello".to_vec(),
+      vary: vec![("accept-encoding".to_string(), "gzip".to_string())],
+      stored_at: 10,
+      expires_at: 20,
+    };
+    let encoded = entry.encode();
+    assert_eq!(CacheEntry::decode(&encoded), Some(entry.clone()));
+    assert_eq!(CacheEntry::decode(&encoded[..encoded.len() - 1]), None);
+    assert_eq!(CacheEntry::decode(&[]), None);
+    assert!(entry.is_fresh(19));
+    assert!(!entry.is_fresh(20));
+    assert_eq!(entry.age(15), Duration::from_nanos(5));
+    assert_eq!(entry.age(5), Duration::from_nanos(0));
+  }
+
+  #[test]
+  fn parses_cache_control() {
+    assert_eq!(
+      CacheControl::parse("Public, max-age=60, s-maxage=\"120\", Must-Revalidate"),
+      CacheControl {
+        public: true,
+        must_revalidate: true,
+        max_age: Some(60),
+        s_maxage: Some(120),
+        ..CacheControl::default()
+      }
+    );
+    assert_eq!(
+      CacheControl::parse("no-store,no-cache, private, max-age=soon"),
+      CacheControl {
+        no_store: true,
+        no_cache: true,
+        private: true,
+        ..CacheControl::default()
+      }
+    );
+    assert_eq!(CacheControl::parse(""), CacheControl::default());
+  }
+
+  fn policy(
+    status_code: u32,
+    headers: &[(&str, &str)],
+    authorized: bool,
+  ) -> Option<(Duration, Vec<String>)> {
+    let headers: Vec<(String, String)> = headers
+      .iter()
+      .map(|(k, v)| (k.to_string(), v.to_string()))
+      .collect();
+    storage_policy(status_code, &headers, authorized, Duration::from_secs(60))
+  }
+
+  #[test]
+  fn stores_cacheable_statuses_only() {
+    for status_code in &[200, 203, 204, 300, 301, 308, 404, 410] {
+      assert_eq!(
+        policy(*status_code, &[], false),
+        Some((Duration::from_secs(60), Vec::new()))
+      );
+    }
+    for status_code in &[201, 302, 304, 400, 500, 503] {
+      assert_eq!(policy(*status_code, &[], false), None);
+    }
+  }
+
+  #[test]
+  fn skips_private_and_uncacheable_responses() {
+    let skipped: [&[(&str, &str)]; 7] = [
+      &[("cache-control", "private, max-age=60")],
+      &[("cache-control", "no-store")],
+      &[("Cache-Control", "no-cache")],
+      &[("cache-control", "max-age=0")],
+      &[("set-cookie", "session=1")],
+      &[("vary", "*")],
+      &[("vary", "accept-encoding, *")],
+    ];
+    for headers in skipped.iter() {
+      assert_eq!(policy(200, headers, false), None, "{:?}", headers);
+    }
+    assert_eq!(
+      policy(
+        200,
+        &[
+          ("cache-control", "max-age=30, s-maxage=120"),
+          ("Vary", "Accept-Encoding, ,User-Agent")
+        ],
+        false
+      ),
+      Some((
+        Duration::from_secs(120),
+        vec!["accept-encoding".to_string(), "user-agent".to_string()]
+      ))
+    );
+  }
+
+  #[test]
+  fn stores_authorized_responses_only_when_shareable() {
+    assert_eq!(policy(200, &[], true), None);
+    assert_eq!(policy(200, &[("cache-control", "max-age=30")], true), None);
+    for cache_control in &["public", "must-revalidate, max-age=30", "s-maxage=30"] {
+      assert!(policy(200, &[("cache-control", cache_control)], true).is_some());
+    }
+  }
+
+  #[test]
+  fn index_evicts_the_oldest_keys() {
+    let (index, evicted) = update_index(None, "a", 2);
+    assert_eq!(index, b"a");
+    assert!(evicted.is_empty());
+    let (index, evicted) = update_index(Some(&index), "b", 2);
+    assert_eq!(index, b"a\nb");
+    assert!(evicted.is_empty());
+    // Storing a key again makes it the newest.
+    let (index, evicted) = update_index(Some(&index), "a", 2);
+    assert_eq!(index, b"b\na");
+    assert!(evicted.is_empty());
+    let (index, evicted) = update_index(Some(&index), "c", 2);
+    assert_eq!(index, b"a\nc");
+    assert_eq!(evicted, vec!["b".to_string()]);
+    // Blank lines in the index are ignored.
+    let (index, evicted) = update_index(Some(b"\nx\n\ny\nz"), "c", 1);
+    assert_eq!(index, b"c");
+    assert_eq!(evicted, vec!["x", "y", "z"]);
+  }
+}
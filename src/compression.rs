@@ -0,0 +1,547 @@
+use crate::body::{BodyHandler, BodyPolicy};
+use crate::executor::StreamDirection;
+use crate::payload::*;
+use crate::reply::LocalReply;
+use crate::types::*;
+use log::warn;
+use std::io::Write;
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContentEncoding {
+  #[cfg(feature = "gzip")]
+  Gzip,
+  /// zlib-wrapped deflate, as meant by HTTP.
+  #[cfg(feature = "gzip")]
+  Deflate,
+  #[cfg(feature = "brotli")]
+  Brotli,
+}
+
+impl ContentEncoding {
+  /// Parses a `content-encoding` value. Stacked encodings are not supported.
+  pub fn parse(value: &str) -> Option<ContentEncoding> {
+    match value.trim().to_ascii_lowercase().as_str() {
+      #[cfg(feature = "gzip")]
+      "gzip" | "x-gzip" => Some(ContentEncoding::Gzip),
+      #[cfg(feature = "gzip")]
+      "deflate" => Some(ContentEncoding::Deflate),
+      #[cfg(feature = "brotli")]
+      "br" => Some(ContentEncoding::Brotli),
+      _ => None,
+    }
+  }
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      #[cfg(feature = "gzip")]
+      ContentEncoding::Gzip => "gzip",
+      #[cfg(feature = "gzip")]
+      ContentEncoding::Deflate => "deflate",
+      #[cfg(feature = "brotli")]
+      ContentEncoding::Brotli => "br",
+    }
+  }
+}
+
+enum DecoderInner {
+  #[cfg(feature = "gzip")]
+  Gzip(flate2::write::GzDecoder<Vec<u8>>),
+  #[cfg(feature = "gzip")]
+  Deflate(flate2::write::ZlibDecoder<Vec<u8>>),
+  #[cfg(feature = "brotli")]
+  Brotli(Box<brotli::DecompressorWriter<Vec<u8>>>),
+}
+
+/// Decompresses a body pushed chunk by chunk. Every push returns all the data decompressed so
+/// far.
+pub struct StreamDecoder {
+  inner: DecoderInner,
+}
+
+impl StreamDecoder {
+  pub fn new(encoding: ContentEncoding) -> StreamDecoder {
+    let inner = match encoding {
+      #[cfg(feature = "gzip")]
+      ContentEncoding::Gzip => DecoderInner::Gzip(flate2::write::GzDecoder::new(Vec::new())),
+      #[cfg(feature = "gzip")]
+      ContentEncoding::Deflate => {
+        DecoderInner::Deflate(flate2::write::ZlibDecoder::new(Vec::new()))
+      }
+      #[cfg(feature = "brotli")]
+      ContentEncoding::Brotli => {
+        DecoderInner::Brotli(Box::new(brotli::DecompressorWriter::new(Vec::new(), 4096)))
+      }
+    };
+    StreamDecoder { inner }
+  }
+
+  /// Returns the data decompressed from `data`.
+  pub fn push(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+    let output = match &mut self.inner {
+      #[cfg(feature = "gzip")]
+      DecoderInner::Gzip(d) => d
+        .write_all(data)
+        .and_then(|_| d.flush())
+        .map(|_| std::mem::take(d.get_mut())),
+      #[cfg(feature = "gzip")]
+      DecoderInner::Deflate(d) => d
+        .write_all(data)
+        .and_then(|_| d.flush())
+        .map(|_| std::mem::take(d.get_mut())),
+      #[cfg(feature = "brotli")]
+      DecoderInner::Brotli(d) => d
+        .write_all(data)
+        .and_then(|_| d.flush())
+        .map(|_| std::mem::take(d.get_mut())),
+    };
+    output.map_err(|e| format!("failed to decompress: {}", e))
+  }
+
+  /// Ends the stream and returns the remaining data.
+  pub fn finish(self) -> Result<Vec<u8>, String> {
+    let output = match self.inner {
+      #[cfg(feature = "gzip")]
+      DecoderInner::Gzip(d) => d.finish(),
+      #[cfg(feature = "gzip")]
+      DecoderInner::Deflate(d) => d.finish(),
+      #[cfg(feature = "brotli")]
+      DecoderInner::Brotli(mut d) => d.close().and_then(|_| {
+        d.into_inner()
+          .map_err(|_| std::io::ErrorKind::InvalidData.into())
+      }),
+    };
+    output.map_err(|e| format!("failed to decompress: {}", e))
+  }
+}
+
+enum EncoderInner {
+  #[cfg(feature = "gzip")]
+  Gzip(flate2::write::GzEncoder<Vec<u8>>),
+  #[cfg(feature = "gzip")]
+  Deflate(flate2::write::ZlibEncoder<Vec<u8>>),
+  #[cfg(feature = "brotli")]
+  Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+/// Compresses a body pushed chunk by chunk. Every push is flushed, so that chunks are not held
+/// back from the peer.
+pub struct StreamEncoder {
+  inner: EncoderInner,
+}
+
+impl StreamEncoder {
+  pub fn new(encoding: ContentEncoding) -> StreamEncoder {
+    let inner = match encoding {
+      #[cfg(feature = "gzip")]
+      ContentEncoding::Gzip => EncoderInner::Gzip(flate2::write::GzEncoder::new(
+        Vec::new(),
+        flate2::Compression::default(),
+      )),
+      #[cfg(feature = "gzip")]
+      ContentEncoding::Deflate => EncoderInner::Deflate(flate2::write::ZlibEncoder::new(
+        Vec::new(),
+        flate2::Compression::default(),
+      )),
+      #[cfg(feature = "brotli")]
+      ContentEncoding::Brotli => EncoderInner::Brotli(Box::new(brotli::CompressorWriter::new(
+        Vec::new(),
+        4096,
+        5,
+        22,
+      ))),
+    };
+    StreamEncoder { inner }
+  }
+
+  /// Returns the data compressed from `data`.
+  pub fn push(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+    let output = match &mut self.inner {
+      #[cfg(feature = "gzip")]
+      EncoderInner::Gzip(e) => e
+        .write_all(data)
+        .and_then(|_| e.flush())
+        .map(|_| std::mem::take(e.get_mut())),
+      #[cfg(feature = "gzip")]
+      EncoderInner::Deflate(e) => e
+        .write_all(data)
+        .and_then(|_| e.flush())
+        .map(|_| std::mem::take(e.get_mut())),
+      #[cfg(feature = "brotli")]
+      EncoderInner::Brotli(e) => e
+        .write_all(data)
+        .and_then(|_| e.flush())
+        .map(|_| std::mem::take(e.get_mut())),
+    };
+    output.map_err(|e| format!("failed to compress: {}", e))
+  }
+
+  /// Ends the stream and returns the remaining data.
+  pub fn finish(self) -> Result<Vec<u8>, String> {
+    let output = match self.inner {
+      #[cfg(feature = "gzip")]
+      EncoderInner::Gzip(e) => e.finish(),
+      #[cfg(feature = "gzip")]
+      EncoderInner::Deflate(e) => e.finish(),
+      #[cfg(feature = "brotli")]
+      EncoderInner::Brotli(e) => Ok::<_, std::io::Error>(e.into_inner()),
+    };
+    output.map_err(|e| format!("failed to compress: {}", e))
+  }
+}
+
+#[derive(Default)]
+struct CodecState {
+  decoder: Option<StreamDecoder>,
+  encoder: Option<StreamEncoder>,
+  failed: bool,
+}
+
+/// Decodes an encoded body so that it can be inspected or modified, and encodes it again.
+///
+/// `on_headers` has to be called from the headers callback of the direction. It removes
+/// `content-length`, as the length of the body changes, and `content-encoding` as well when the
+/// body is forwarded decoded.
+///
+/// A body which fails to decode or encode is never forwarded partially transcoded: the stream
+/// is answered with 400 for requests and 502 for responses, and the rest of the body is dropped.
+/// Envoy resets the stream instead if the response headers have already been sent.
+///
+/// Streams ending with trailers need `on_trailers` as well, so that the end of the body is
+/// decoded and encoded.
+pub struct CompressedBody {
+  body: BodyHandler,
+  recompress: bool,
+  state: Mutex<CodecState>,
+}
+
+impl CompressedBody {
+  pub fn new(direction: StreamDirection, policy: BodyPolicy) -> CompressedBody {
+    CompressedBody {
+      body: BodyHandler::new(direction, policy),
+      recompress: true,
+      state: Mutex::new(CodecState::default()),
+    }
+  }
+
+  pub fn request(policy: BodyPolicy) -> CompressedBody {
+    CompressedBody::new(StreamDirection::Request, policy)
+  }
+
+  pub fn response(policy: BodyPolicy) -> CompressedBody {
+    CompressedBody::new(StreamDirection::Response, policy)
+  }
+
+  /// Whether the body is encoded again before being forwarded. Defaults to true.
+  pub fn recompress(mut self, recompress: bool) -> CompressedBody {
+    self.recompress = recompress;
+    self
+  }
+
+  pub fn handler(&self) -> &BodyHandler {
+    &self.body
+  }
+
+  fn header(&self, key: &str) -> Option<String> {
+    let value = match self.body.direction() {
+      StreamDirection::Request => get_request_header(key.to_string()),
+      StreamDirection::Response => get_response_header(key.to_string()),
+    };
+    match value.map(|v| v.to_string()) {
+      Ok(v) if !v.is_empty() => Some(v),
+      _ => None,
+    }
+  }
+
+  fn remove_header(&self, key: &str) {
+    let result = match self.body.direction() {
+      StreamDirection::Request => remove_request_header(key.to_string()),
+      StreamDirection::Response => remove_response_header(key.to_string()),
+    };
+    match result {
+      WasmResult::Ok | WasmResult::NotFound => {}
+      r => warn!("failed to remove {}: {}", key, r),
+    }
+  }
+
+  /// Prepares the headers and returns the encoding of the body, if it is supported.
+  pub fn on_headers(&self) -> Option<ContentEncoding> {
+    let encoding = self
+      .header("content-encoding")
+      .and_then(|v| ContentEncoding::parse(&v));
+    let mut state = self.state.lock().unwrap();
+    *state = CodecState::default();
+    let encoding = encoding?;
+    self.remove_header("content-length");
+    if self.recompress {
+      state.encoder = Some(StreamEncoder::new(encoding));
+    } else {
+      self.remove_header("content-encoding");
+    }
+    state.decoder = Some(StreamDecoder::new(encoding));
+    Some(encoding)
+  }
+
+  /// Handles a body callback. `f` receives the decoded data, as selected by the policy, along
+  /// with whether the stream ends, and may modify it. Bodies without a supported encoding are
+  /// given to `f` as they are.
+  pub fn on_body<F>(
+    &self,
+    body_buffer_length: usize,
+    end_of_stream: bool,
+    mut f: F,
+  ) -> FilterDataStatus
+  where
+    F: FnMut(&mut Vec<u8>, bool),
+  {
+    if self.state.lock().unwrap().failed {
+      return FilterDataStatus::StopIterationNoBuffer;
+    }
+    let status = self
+      .body
+      .on_body(body_buffer_length, end_of_stream, |data, end_of_stream| {
+        self.handle(data, end_of_stream, &mut f)
+      });
+    if !self.state.lock().unwrap().failed {
+      return status;
+    }
+    self.fail();
+    FilterDataStatus::StopIterationNoBuffer
+  }
+
+  /// Handles a trailers callback, which ends streams such as gRPC ones without delivering
+  /// `end_of_stream` to a body callback, and flushes the decoder and encoder. With `Buffer` and
+  /// `Watermark`, `f` receives the buffered data as in `on_body` at the end of the stream. With
+  /// `Chunked`, `f` receives the data still held by the decoder, and what the encoder still held
+  /// is appended to the body.
+  pub fn on_trailers<F>(&self, mut f: F) -> FilterTrailersStatus
+  where
+    F: FnMut(&mut Vec<u8>, bool),
+  {
+    if self.state.lock().unwrap().failed {
+      return FilterTrailersStatus::StopIteration;
+    }
+    match self.body.policy() {
+      BodyPolicy::Chunked => {
+        if let Some(tail) = self.handle(&[], true, &mut f) {
+          if !tail.is_empty() && !self.state.lock().unwrap().failed {
+            match self.body.append(&tail) {
+              WasmResult::Ok => {}
+              r => warn!(
+                "failed to append to {:?} body: {}",
+                self.body.direction(),
+                r
+              ),
+            }
+          }
+        }
+      }
+      _ => {
+        self
+          .body
+          .on_trailers(|data, end_of_stream| self.handle(data, end_of_stream, &mut f));
+      }
+    }
+    if !self.state.lock().unwrap().failed {
+      return FilterTrailersStatus::Continue;
+    }
+    self.fail();
+    FilterTrailersStatus::StopIteration
+  }
+
+  // Passes `data` through `f`, transcoding it if the body is encoded, and returns the
+  // replacement. On failure, the state is marked as failed and the data is dropped.
+  fn handle<F>(&self, data: &[u8], end_of_stream: bool, f: &mut F) -> Option<Vec<u8>>
+  where
+    F: FnMut(&mut Vec<u8>, bool),
+  {
+    let mut state = self.state.lock().unwrap();
+    if state.decoder.is_none() {
+      let mut plain = data.to_vec();
+      f(&mut plain, end_of_stream);
+      return if plain != data { Some(plain) } else { None };
+    }
+    match self.transcode(&mut state, data, end_of_stream, f) {
+      Ok(output) => Some(output),
+      Err(e) => {
+        warn!("{:?} body: {}", self.body.direction(), e);
+        *state = CodecState {
+          failed: true,
+          ..CodecState::default()
+        };
+        Some(Vec::new())
+      }
+    }
+  }
+
+  fn fail(&self) {
+    let reply = match self.body.direction() {
+      StreamDirection::Request => LocalReply::bad_request(),
+      StreamDirection::Response => LocalReply::error_page(502),
+    };
+    match reply.details("content_transcoding_failed").send() {
+      WasmResult::Ok => {}
+      r => warn!("failed to send local reply: {}", r),
+    }
+  }
+
+  fn transcode<F>(
+    &self,
+    state: &mut CodecState,
+    data: &[u8],
+    end_of_stream: bool,
+    f: &mut F,
+  ) -> Result<Vec<u8>, String>
+  where
+    F: FnMut(&mut Vec<u8>, bool),
+  {
+    let mut plain = match state.decoder.as_mut() {
+      Some(decoder) => decoder.push(data)?,
+      None => data.to_vec(),
+    };
+    if end_of_stream {
+      if let Some(decoder) = state.decoder.take() {
+        plain.extend(decoder.finish()?);
+      }
+    }
+    f(&mut plain, end_of_stream);
+    let mut output = match state.encoder.as_mut() {
+      Some(encoder) => encoder.push(&plain)?,
+      None => plain,
+    };
+    if end_of_stream {
+      if let Some(encoder) = state.encoder.take() {
+        output.extend(encoder.finish()?);
+      }
+    }
+    Ok(output)
+  }
+}
+
+#[cfg(all(test, any(feature = "gzip", feature = "brotli")))]
+mod tests {
+  use super::*;
+
+  fn encodings() -> Vec<ContentEncoding> {
+    vec![
+      #[cfg(feature = "gzip")]
+      ContentEncoding::Gzip,
+      #[cfg(feature = "gzip")]
+      ContentEncoding::Deflate,
+      #[cfg(feature = "brotli")]
+      ContentEncoding::Brotli,
+    ]
+  }
+
+  fn body() -> Vec<u8> {
+    (0..20_000u32)
+      .flat_map(|i| (i % 251).to_le_bytes())
+      .collect()
+  }
+
+  fn encode(encoding: ContentEncoding, data: &[u8]) -> Vec<u8> {
+    let mut encoder = StreamEncoder::new(encoding);
+    let mut encoded = Vec::new();
+    for chunk in data.chunks(7_000) {
+      encoded.extend(encoder.push(chunk).unwrap());
+    }
+    encoded.extend(encoder.finish().unwrap());
+    encoded
+  }
+
+  fn decode(encoding: ContentEncoding, data: &[u8]) -> Vec<u8> {
+    let mut decoder = StreamDecoder::new(encoding);
+    let mut decoded = Vec::new();
+    for chunk in data.chunks(1_000) {
+      decoded.extend(decoder.push(chunk).unwrap());
+    }
+    decoded.extend(decoder.finish().unwrap());
+    decoded
+  }
+
+  #[test]
+  fn streams_round_trip() {
+    for encoding in encodings() {
+      let body = body();
+      let encoded = encode(encoding, &body);
+      assert!(encoded.len() < body.len(), "{:?}", encoding);
+      assert_eq!(decode(encoding, &encoded), body, "{:?}", encoding);
+      assert_eq!(decode(encoding, &encode(encoding, &[])), Vec::<u8>::new());
+    }
+  }
+
+  #[test]
+  fn pushed_chunks_are_flushed() {
+    for encoding in encodings() {
+      let mut encoder = StreamEncoder::new(encoding);
+      let mut decoder = StreamDecoder::new(encoding);
+      let chunk = encoder.push(b"hello").unwrap();
+      assert_eq!(decoder.push(&chunk).unwrap(), b"hello", "{:?}", encoding);
+    }
+  }
+
+  #[test]
+  fn rejects_corrupted_streams() {
+    for encoding in encodings() {
+      let mut decoder = StreamDecoder::new(encoding);
+      assert!(decoder.push(&[0xff; 64]).is_err(), "{:?}", encoding);
+    }
+  }
+
+  #[cfg(feature = "gzip")]
+  #[test]
+  fn rejects_truncated_gzip_streams() {
+    let mut encoded = encode(ContentEncoding::Gzip, &body());
+    encoded.truncate(encoded.len() - 4);
+    let mut decoder = StreamDecoder::new(ContentEncoding::Gzip);
+    assert!(decoder.push(&encoded).is_ok());
+    assert!(decoder.finish().is_err());
+  }
+
+  // A chunked body ending with trailers: every chunk is transcoded without `end_of_stream`, and
+  // `on_trailers` flushes both codecs with an empty chunk.
+  #[test]
+  fn trailers_flush_chunked_bodies() {
+    for encoding in encodings() {
+      let compressed = CompressedBody::response(BodyPolicy::Chunked);
+      {
+        let mut state = compressed.state.lock().unwrap();
+        state.decoder = Some(StreamDecoder::new(encoding));
+        state.encoder = Some(StreamEncoder::new(encoding));
+      }
+      let body = body();
+      let mut seen = Vec::new();
+      let mut ended = false;
+      let mut f = |data: &mut Vec<u8>, end_of_stream: bool| {
+        seen.extend_from_slice(data);
+        ended = end_of_stream;
+        data.make_ascii_uppercase();
+      };
+      let mut forwarded = Vec::new();
+      for chunk in encode(encoding, &body).chunks(3_000) {
+        forwarded.extend(compressed.handle(chunk, false, &mut f).unwrap());
+      }
+      forwarded.extend(compressed.handle(&[], true, &mut f).unwrap());
+      assert!(ended);
+      assert_eq!(seen, body, "{:?}", encoding);
+      assert_eq!(
+        decode(encoding, &forwarded),
+        body.to_ascii_uppercase(),
+        "{:?}",
+        encoding
+      );
+      assert!(!compressed.state.lock().unwrap().failed);
+    }
+  }
+
+  #[test]
+  fn failed_bodies_are_dropped() {
+    for encoding in encodings() {
+      let compressed = CompressedBody::request(BodyPolicy::Chunked);
+      compressed.state.lock().unwrap().decoder = Some(StreamDecoder::new(encoding));
+      let output = compressed.handle(&[0xff; 64], false, &mut |_, _| {});
+      assert_eq!(output, Some(Vec::new()), "{:?}", encoding);
+      assert!(compressed.state.lock().unwrap().failed);
+    }
+  }
+}
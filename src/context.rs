@@ -1,11 +1,16 @@
+use crate::extensions::Extensions;
 use crate::host::*;
 use crate::types::*;
 use lazy_static::lazy_static;
+use log::{error, warn};
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::ffi::CString;
 use std::os::raw::c_char;
 use std::ptr::null_mut;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 pub trait RootContext {
   fn on_start(&self, _configuration_size: u32) -> u32 {
@@ -15,6 +20,24 @@ pub trait RootContext {
     true
   }
   fn on_tick(&self) {}
+  fn on_queue_ready(&self, _queue_id: u32) {}
+  fn on_http_call_response(
+    &self,
+    _token: u32,
+    _num_headers: u32,
+    _body_size: usize,
+    _num_trailers: u32,
+  ) {
+  }
+  fn on_grpc_receive(&self, _token: u32, _response_size: usize) {}
+  fn on_grpc_close(&self, _token: u32, _status_code: GrpcStatus) {}
+  // Called when the plugin is loaded as an access logger, see `access_log`.
+  fn on_log(&self) {}
+  /// Log level of this plugin, queried after `on_configure`. It is usually read from the
+  /// plugin configuration, see `Logger::parse_level`.
+  fn log_level(&self) -> Option<log::LevelFilter> {
+    None
+  }
 }
 
 pub trait Context {
@@ -49,6 +72,31 @@ pub trait Context {
   fn on_response_body(&self, _body_buffer_length: usize, _is_stream_end: bool) -> FilterDataStatus {
     FilterDataStatus::Continue
   }
+  // Called for callouts which are not awaited through the executor.
+  fn on_http_call_response(
+    &self,
+    _token: u32,
+    _num_headers: u32,
+    _body_size: usize,
+    _num_trailers: u32,
+  ) {
+  }
+  fn on_grpc_receive(&self, _token: u32, _response_size: usize) {}
+  fn on_grpc_close(&self, _token: u32, _status_code: GrpcStatus) {}
+  // Called once the stream is complete, with headers and trailers readable but immutable.
+  fn on_log(&self) {}
+}
+
+/// Root context used when no factory matches the root id. Accepts any configuration.
+pub struct NoopRootContext {}
+
+impl RootContext for NoopRootContext {}
+
+/// Context which lets every stream pass through untouched.
+pub struct NoopContext {}
+
+impl Context for NoopContext {
+  fn on_create(&self) {}
 }
 
 pub trait RootContextFactory {
@@ -61,27 +109,123 @@ pub trait ContextFactory {
   ) -> Arc<dyn Context + Sync + Send>;
 }
 
+impl<F> RootContextFactory for F
+where
+  F: Fn() -> Arc<dyn RootContext + Sync + Send>,
+{
+  fn create(&self) -> Arc<dyn RootContext + Sync + Send> {
+    self()
+  }
+}
+
+impl<F> ContextFactory for F
+where
+  F: Fn(Arc<dyn RootContext + Sync + Send>) -> Arc<dyn Context + Sync + Send>,
+{
+  fn create(
+    &self,
+    _root_context: Arc<dyn RootContext + Sync + Send>,
+  ) -> Arc<dyn Context + Sync + Send> {
+    self(_root_context)
+  }
+}
+
 lazy_static! {
-  static ref ROOT_CONTEXT_FACTORY_MAP: Mutex<HashMap<&'static str, &'static Box<dyn RootContextFactory + Sync + Send>>> =
+  static ref ROOT_CONTEXT_FACTORY_MAP: Mutex<HashMap<String, Arc<dyn RootContextFactory + Sync + Send>>> =
     Mutex::new(HashMap::new());
-  static ref CONTEXT_FACTORY_MAP: Mutex<HashMap<&'static str, &'static Box<dyn ContextFactory + Sync + Send>>> =
+  static ref CONTEXT_FACTORY_MAP: Mutex<HashMap<String, Arc<dyn ContextFactory + Sync + Send>>> =
     Mutex::new(HashMap::new());
   static ref ROOT_CONTEXT_MAP: Mutex<HashMap<u32, Arc<dyn RootContext + Sync + Send>>> =
     Mutex::new(HashMap::new());
   pub static ref CONTEXT_MAP: Mutex<HashMap<u32, Arc<dyn Context + Sync + Send>>> =
     Mutex::new(HashMap::new());
+  static ref DEFAULT_ROOT_CONTEXT_FACTORY: Mutex<Option<Arc<dyn RootContextFactory + Sync + Send>>> =
+    Mutex::new(None);
+  static ref DEFAULT_CONTEXT_FACTORY: Mutex<Option<Arc<dyn ContextFactory + Sync + Send>>> =
+    Mutex::new(None);
+  // Maps every context id, including root context ids, to the id of its root context.
+  static ref ROOT_CONTEXT_ID_MAP: Mutex<HashMap<u32, u32>> = Mutex::new(HashMap::new());
+  // Maps root context ids to the root id they were created for.
+  static ref ROOT_ID_MAP: Mutex<HashMap<u32, String>> = Mutex::new(HashMap::new());
+  // Request-scoped values of each context, created on first use.
+  static ref EXTENSIONS_MAP: Mutex<HashMap<u32, Arc<Mutex<Extensions>>>> =
+    Mutex::new(HashMap::new());
+}
+
+static ACTIVE_CONTEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Records the context whose callback is being dispatched.
+pub fn set_active_context(context_id: u32) {
+  ACTIVE_CONTEXT_ID.store(context_id, Ordering::Relaxed);
+}
+
+pub fn active_context_id() -> u32 {
+  ACTIVE_CONTEXT_ID.load(Ordering::Relaxed)
 }
 
-pub fn register_factory(
-  _root_id: &'static str,
-  _cf: &'static Box<dyn ContextFactory + Sync + Send>,
-  _rcf: &'static Box<dyn RootContextFactory + Sync + Send>,
-) {
+/// Root context id of the active context. Returns `None` when it is unknown or when the
+/// lookup would block, so that it can be used from the logger.
+pub fn active_root_context_id() -> Option<u32> {
+  match ROOT_CONTEXT_ID_MAP.try_lock() {
+    Ok(map) => map.get(&active_context_id()).copied(),
+    Err(_) => None,
+  }
+}
+
+pub fn is_root_context(context_id: u32) -> bool {
+  ROOT_CONTEXT_MAP.lock().unwrap().contains_key(&context_id)
+}
+
+/// Root id of the plugin owning `root_context_id`. Returns `None` when it is unknown or when
+/// the lookup would block.
+pub fn root_id(root_context_id: u32) -> Option<String> {
+  match ROOT_ID_MAP.try_lock() {
+    Ok(map) => map.get(&root_context_id).cloned(),
+    Err(_) => None,
+  }
+}
+
+/// Registers the factories of the filter selected by `root_id` in the plugin configuration.
+/// Call it from `_start` once per filter to ship several filters in one module. Factories are
+/// either types implementing the factory traits or closures.
+pub fn register_factory<C, R>(_root_id: &str, _cf: C, _rcf: R)
+where
+  C: ContextFactory + Sync + Send + 'static,
+  R: RootContextFactory + Sync + Send + 'static,
+{
   ROOT_CONTEXT_FACTORY_MAP
     .lock()
     .unwrap()
-    .insert(_root_id, _rcf);
-  CONTEXT_FACTORY_MAP.lock().unwrap().insert(_root_id, _cf);
+    .insert(_root_id.to_string(), Arc::new(_rcf));
+  CONTEXT_FACTORY_MAP
+    .lock()
+    .unwrap()
+    .insert(_root_id.to_string(), Arc::new(_cf));
+}
+
+/// Registers the factories used for root ids which have no factory of their own.
+pub fn register_default_factory<C, R>(_cf: C, _rcf: R)
+where
+  C: ContextFactory + Sync + Send + 'static,
+  R: RootContextFactory + Sync + Send + 'static,
+{
+  *DEFAULT_ROOT_CONTEXT_FACTORY.lock().unwrap() = Some(Arc::new(_rcf));
+  *DEFAULT_CONTEXT_FACTORY.lock().unwrap() = Some(Arc::new(_cf));
+}
+
+/// Makes the host call `on_tick` of the current root context every `period`. A zero period
+/// disables the timer.
+pub fn set_tick_period(period: Duration) -> WasmResult {
+  unsafe {
+    let code = proxy_set_tick_period_milliseconds(period.as_millis() as u32);
+    match WasmResult::try_from(code) {
+      Ok(r) => r,
+      Err(e) => {
+        warn!("failed to convert: {}", e);
+        WasmResult::InternalFailure
+      }
+    }
+  }
 }
 
 fn current_root_id_str() -> String {
@@ -100,66 +244,179 @@ fn current_root_id_str() -> String {
   }
 }
 
+fn registered_root_ids() -> String {
+  let mut root_ids: Vec<String> = ROOT_CONTEXT_FACTORY_MAP
+    .lock()
+    .unwrap()
+    .keys()
+    .cloned()
+    .collect();
+  root_ids.sort_unstable();
+  format!("[{}]", root_ids.join(", "))
+}
+
+// Factories are cloned out of the maps so that no lock is held while user code runs.
+fn create_root_context(root_id_str: &str) -> Arc<dyn RootContext + Sync + Send> {
+  let root_factory = ROOT_CONTEXT_FACTORY_MAP
+    .lock()
+    .unwrap()
+    .get(root_id_str)
+    .map(Arc::clone);
+  if let Some(root_factory) = root_factory {
+    return root_factory.create();
+  }
+  let default_root_factory = DEFAULT_ROOT_CONTEXT_FACTORY.lock().unwrap().clone();
+  if let Some(root_factory) = default_root_factory {
+    warn!(
+      "no factory registered for root id \"{}\", using the default factory",
+      root_id_str
+    );
+    return root_factory.create();
+  }
+  error!(
+    "no factory registered for root id \"{}\", registered root ids are {}; the plugin will pass through all traffic",
+    root_id_str,
+    registered_root_ids()
+  );
+  Arc::new(NoopRootContext {})
+}
+
+fn create_context(
+  root_id_str: &str,
+  root_context: Arc<dyn RootContext + Sync + Send>,
+) -> Arc<dyn Context + Sync + Send> {
+  let factory = CONTEXT_FACTORY_MAP
+    .lock()
+    .unwrap()
+    .get(root_id_str)
+    .map(Arc::clone);
+  if let Some(factory) = factory {
+    return factory.create(root_context);
+  }
+  let default_factory = DEFAULT_CONTEXT_FACTORY.lock().unwrap().clone();
+  if let Some(factory) = default_factory {
+    return factory.create(root_context);
+  }
+  Arc::new(NoopContext {})
+}
+
 pub fn ensure_root_context(root_context_id: u32) -> Arc<dyn RootContext + Sync + Send> {
-  let root_context = match ROOT_CONTEXT_MAP.lock().unwrap().get(&root_context_id) {
-    Some(x) => Arc::clone(x),
-    None => {
-      let root_id_str = current_root_id_str();
-      let root_context = match ROOT_CONTEXT_FACTORY_MAP
-        .lock()
-        .unwrap()
-        .get(&root_id_str.as_ref())
-      {
-        Some(root_factory) => root_factory.create(),
-        None => unimplemented!(),
-      };
-      root_context
-    }
-  };
-  let mut locked_root_context_map = ROOT_CONTEXT_MAP.lock().unwrap();
-  if !locked_root_context_map.contains_key(&root_context_id) {
-    locked_root_context_map.insert(root_context_id, Arc::clone(&root_context));
+  ROOT_CONTEXT_ID_MAP
+    .lock()
+    .unwrap()
+    .insert(root_context_id, root_context_id);
+  let existing = ROOT_CONTEXT_MAP
+    .lock()
+    .unwrap()
+    .get(&root_context_id)
+    .map(Arc::clone);
+  if let Some(root_context) = existing {
+    return root_context;
   }
-  root_context
+  let root_id_str = current_root_id_str();
+  ROOT_ID_MAP
+    .lock()
+    .unwrap()
+    .insert(root_context_id, root_id_str.clone());
+  let root_context = create_root_context(&root_id_str);
+  ROOT_CONTEXT_MAP
+    .lock()
+    .unwrap()
+    .entry(root_context_id)
+    .or_insert(root_context)
+    .clone()
 }
 
 pub fn ensure_context(context_id: u32, root_context_id: u32) -> Arc<dyn Context + Sync + Send> {
-  let root_id_str = current_root_id_str();
-  let context = match CONTEXT_MAP.lock().unwrap().get(&context_id) {
-    Some(x) => Arc::clone(x),
-    None => {
-      let root_context = match ROOT_CONTEXT_MAP.lock().unwrap().get(&root_context_id) {
-        Some(root_context) => Arc::clone(root_context),
-        None => unimplemented!(),
-      };
-      let context = match CONTEXT_FACTORY_MAP
+  ROOT_CONTEXT_ID_MAP
+    .lock()
+    .unwrap()
+    .insert(context_id, root_context_id);
+  let existing = CONTEXT_MAP.lock().unwrap().get(&context_id).map(Arc::clone);
+  if let Some(context) = existing {
+    return context;
+  }
+  let root_context = ROOT_CONTEXT_MAP
+    .lock()
+    .unwrap()
+    .get(&root_context_id)
+    .map(Arc::clone);
+  let context = match root_context {
+    Some(root_context) => {
+      // The root id is cached when the root context is created, so that it is not queried
+      // from the host for every stream.
+      let root_id_str = ROOT_ID_MAP
         .lock()
         .unwrap()
-        .get(&root_id_str.as_ref())
-      {
-        Some(factory) => factory.create(root_context),
-        None => unimplemented!(),
-      };
-      context
+        .get(&root_context_id)
+        .cloned()
+        .unwrap_or_default();
+      create_context(&root_id_str, root_context)
+    }
+    None => {
+      error!(
+        "unknown root context id {} for context id {}; the stream will pass through",
+        root_context_id, context_id
+      );
+      Arc::new(NoopContext {})
     }
   };
-  let mut locked_context_map = CONTEXT_MAP.lock().unwrap();
-  if !locked_context_map.contains_key(&context_id) {
-    locked_context_map.insert(context_id, Arc::clone(&context));
-  }
-  context
+  CONTEXT_MAP
+    .lock()
+    .unwrap()
+    .entry(context_id)
+    .or_insert(context)
+    .clone()
+}
+
+/// Returns the extensions of `context_id`, creating them if needed.
+pub fn context_extensions(context_id: u32) -> Arc<Mutex<Extensions>> {
+  EXTENSIONS_MAP
+    .lock()
+    .unwrap()
+    .entry(context_id)
+    .or_insert_with(|| Arc::new(Mutex::new(Extensions::new())))
+    .clone()
+}
+
+/// Forgets a deleted context along with its extensions.
+pub fn delete_context(context_id: u32) {
+  // Values are dropped after the locks are released, as their destructors may use the maps.
+  let context = CONTEXT_MAP.lock().unwrap().remove(&context_id);
+  let root_context = ROOT_CONTEXT_MAP.lock().unwrap().remove(&context_id);
+  let extensions = EXTENSIONS_MAP.lock().unwrap().remove(&context_id);
+  ROOT_CONTEXT_ID_MAP.lock().unwrap().remove(&context_id);
+  ROOT_ID_MAP.lock().unwrap().remove(&context_id);
+  drop(extensions);
+  drop(context);
+  drop(root_context);
 }
 
 pub fn get_context(context_id: u32) -> Arc<dyn Context + Sync + Send> {
-  match CONTEXT_MAP.lock().unwrap().get(&context_id) {
-    Some(x) => Arc::clone(x),
-    None => unimplemented!(),
+  let context = CONTEXT_MAP.lock().unwrap().get(&context_id).map(Arc::clone);
+  match context {
+    Some(x) => x,
+    None => {
+      error!("unknown context id {}; the callback is ignored", context_id);
+      Arc::new(NoopContext {})
+    }
   }
 }
 
 pub fn get_root_context(root_context_id: u32) -> Arc<dyn RootContext + Sync + Send> {
-  match ROOT_CONTEXT_MAP.lock().unwrap().get(&root_context_id) {
-    Some(x) => Arc::clone(x),
-    None => unimplemented!(),
+  let root_context = ROOT_CONTEXT_MAP
+    .lock()
+    .unwrap()
+    .get(&root_context_id)
+    .map(Arc::clone);
+  match root_context {
+    Some(x) => x,
+    None => {
+      error!(
+        "unknown root context id {}; the callback is ignored",
+        root_context_id
+      );
+      Arc::new(NoopRootContext {})
+    }
   }
 }
@@ -0,0 +1,186 @@
+fn hex_value(c: u8) -> Option<u8> {
+  match c {
+    b'0'..=b'9' => Some(c - b'0'),
+    b'a'..=b'f' => Some(c - b'a' + 10),
+    b'A'..=b'F' => Some(c - b'A' + 10),
+    _ => None,
+  }
+}
+
+/// Decodes `%XX` escapes. Malformed escapes are kept as they are. When `plus_as_space` is set,
+/// `+` is decoded into a space as in `application/x-www-form-urlencoded`.
+pub fn percent_decode(input: &str, plus_as_space: bool) -> String {
+  let bytes = input.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'%' if i + 2 < bytes.len() => match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+        (Some(h), Some(l)) => {
+          decoded.push(h << 4 | l);
+          i += 3;
+          continue;
+        }
+        _ => decoded.push(b'%'),
+      },
+      b'+' if plus_as_space => decoded.push(b' '),
+      b => decoded.push(b),
+    }
+    i += 1;
+  }
+  String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Encodes every byte except RFC 3986 unreserved characters.
+pub fn percent_encode(input: &str) -> String {
+  let mut encoded = String::with_capacity(input.len());
+  for b in input.bytes() {
+    match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+        encoded.push(b as char)
+      }
+      _ => encoded.push_str(&format!("%{:02X}", b)),
+    }
+  }
+  encoded
+}
+
+/// Encodes a `grpc-message` value as the gRPC over HTTP/2 spec requires: every byte outside
+/// printable ASCII, and `%` itself, is percent-encoded.
+pub fn grpc_message_encode(input: &str) -> String {
+  let mut encoded = String::with_capacity(input.len());
+  for b in input.bytes() {
+    match b {
+      0x20..=0x7e if b != b'%' => encoded.push(b as char),
+      _ => encoded.push_str(&format!("%{:02X}", b)),
+    }
+  }
+  encoded
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+  b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 without padding, as used by `-bin` gRPC metadata.
+pub fn base64_encode(input: &[u8]) -> String {
+  let mut encoded = String::with_capacity(input.len().div_ceil(3) * 4);
+  for chunk in input.chunks(3) {
+    let b = [
+      chunk[0],
+      *chunk.get(1).unwrap_or(&0),
+      *chunk.get(2).unwrap_or(&0),
+    ];
+    let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+    for i in 0..=chunk.len() {
+      encoded.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+    }
+  }
+  encoded
+}
+
+/// Escapes a string so that it can be placed between double quotes in a JSON document.
+pub fn json_escape(input: &str) -> String {
+  let mut escaped = String::with_capacity(input.len());
+  for c in input.chars() {
+    match c {
+      '"' => escaped.push_str("\\\""),
+      '\\' => escaped.push_str("\\\\"),
+      '\n' => escaped.push_str("\\n"),
+      '\r' => escaped.push_str("\\r"),
+      '\t' => escaped.push_str("\\t"),
+      c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+/// Appends `n` as a protobuf varint.
+pub fn encode_varint(mut n: u64, buffer: &mut Vec<u8>) {
+  while n >= 0x80 {
+    buffer.push((n as u8) | 0x80);
+    n >>= 7;
+  }
+  buffer.push(n as u8);
+}
+
+/// Reads a protobuf varint at `*offset` and advances it.
+pub fn decode_varint(buffer: &[u8], offset: &mut usize) -> Option<u64> {
+  let mut n: u64 = 0;
+  for shift in (0..64).step_by(7) {
+    let byte = *buffer.get(*offset)?;
+    *offset += 1;
+    n |= ((byte & 0x7f) as u64) << shift;
+    if byte & 0x80 == 0 {
+      return Some(n);
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn percent_decodes_and_encodes() {
+    assert_eq!(percent_decode("a%20b+c%2fd", false), "a b+c/d");
+    assert_eq!(percent_decode("a+b", true), "a b");
+    assert_eq!(percent_decode("100%", false), "100%");
+    assert_eq!(percent_decode("%4", false), "%4");
+    assert_eq!(percent_decode("%zz%41", false), "%zzA");
+    assert_eq!(percent_encode("a b/é~"), "a%20b%2F%C3%A9~");
+    assert_eq!(percent_decode(&percent_encode("k=v&x"), false), "k=v&x");
+  }
+
+  #[test]
+  fn grpc_message_encodes_non_printable_bytes() {
+    assert_eq!(grpc_message_encode(""), "");
+    assert_eq!(grpc_message_encode("Hi there!"), "Hi there!");
+    assert_eq!(grpc_message_encode("50% off"), "50%25 off");
+    assert_eq!(grpc_message_encode("a\r\nb\t"), "a%0D%0Ab%09");
+    assert_eq!(grpc_message_encode("~\x7f"), "~%7F");
+    assert_eq!(grpc_message_encode("é€"), "%C3%A9%E2%82%AC");
+  }
+
+  #[test]
+  fn json_escapes_quotes_and_control_characters() {
+    assert_eq!(json_escape(""), "");
+    assert_eq!(json_escape("plain é"), "plain é");
+    assert_eq!(json_escape("say \"hi\""), "say \\\"hi\\\"");
+    assert_eq!(json_escape("C:\\dir"), "C:\\\\dir");
+    assert_eq!(json_escape("a\nb\rc\td"), "a\\nb\\rc\\td");
+    assert_eq!(json_escape("\u{0}\u{1f}\u{7f}"), "\\u0000\\u001f\u{7f}");
+  }
+
+  #[test]
+  fn base64_encodes_rfc4648_vectors_without_padding() {
+    let vectors = [
+      ("", ""),
+      ("f", "Zg"),
+      ("fo", "Zm8"),
+      ("foo", "Zm9v"),
+      ("foob", "Zm9vYg"),
+      ("fooba", "Zm9vYmE"),
+      ("foobar", "Zm9vYmFy"),
+    ];
+    for (input, encoded) in vectors.iter() {
+      assert_eq!(base64_encode(input.as_bytes()), *encoded);
+    }
+    // The standard alphabet, not the URL-safe one.
+    assert_eq!(base64_encode(&[0xfb, 0xff, 0xbf]), "+/+/");
+  }
+
+  #[test]
+  fn varints_round_trip() {
+    for n in &[0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+      let mut buffer = Vec::new();
+      encode_varint(*n, &mut buffer);
+      let mut offset = 0;
+      assert_eq!(decode_varint(&buffer, &mut offset), Some(*n));
+      assert_eq!(offset, buffer.len());
+    }
+    let mut offset = 0;
+    assert_eq!(decode_varint(&[0x80], &mut offset), None);
+  }
+}
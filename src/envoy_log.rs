@@ -1,34 +1,293 @@
+use crate::context::*;
+use crate::encoding::*;
 use crate::host::*;
+use crate::payload::get_request_header;
+use crate::time::{current_time, format_rfc3339};
+use crate::types::*;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
 
 /// Logger that integrates with host's logging system.
 pub struct Logger;
 
 static LOGGER: Logger = Logger;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+  /// Message and key-values only. The default.
+  Plain,
+  /// `[root_id=... context_id=... request_id=...] message key=value`
+  Prefixed,
+  /// One JSON object per record, with key-values as fields.
+  Json,
+}
+
+static LOG_FORMAT: AtomicUsize = AtomicUsize::new(LogFormat::Plain as usize);
+static INCLUDE_REQUEST_ID: AtomicBool = AtomicBool::new(false);
+static INCLUDE_TIMESTAMP: AtomicBool = AtomicBool::new(false);
+
+// Level configured on the host, stored as `log::LevelFilter as usize`.
+static HOST_LOG_LEVEL: AtomicUsize = AtomicUsize::new(log::LevelFilter::Trace as usize);
+
+lazy_static! {
+  static ref ROOT_CONTEXT_LOG_LEVELS: Mutex<HashMap<u32, log::LevelFilter>> =
+    Mutex::new(HashMap::new());
+}
+
+/// Logs at the host's `critical` level, which has no `log::Level` counterpart.
+#[macro_export]
+macro_rules! critical {
+  ($($arg:tt)+) => ($crate::envoy_log::log_critical(format_args!($($arg)+)))
+}
+
+pub fn log_critical(args: fmt::Arguments) {
+  let message = args.to_string();
+  unsafe {
+    proxy_log(
+      log_level_to_int(LogLevel::Critical),
+      message.as_ptr(),
+      message.len(),
+    );
+  }
+}
+
 impl Logger {
   pub fn init() -> Result<(), log::SetLoggerError> {
-    log::set_logger(&LOGGER).map(|()| log::set_max_level(log::LevelFilter::Trace))
+    log::set_logger(&LOGGER).map(|()| {
+      log::set_max_level(log::LevelFilter::Trace);
+      Logger::refresh_level();
+      Logger::set_panic_hook();
+    })
   }
 
-  fn proxywasm_loglevel(level: log::Level) -> u32 {
+  /// Reports panics through `proxy_log` at critical level. With `panic = "abort"` the VM traps
+  /// right after the hook returns, so this is the only trace of the panic in Envoy logs.
+  pub fn set_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+      let message = match info.payload().downcast_ref::<&str>() {
+        Some(m) => m.to_string(),
+        None => match info.payload().downcast_ref::<String>() {
+          Some(m) => m.clone(),
+          None => "Box<dyn Any>".to_string(),
+        },
+      };
+      let location = match info.location() {
+        Some(l) => format!(" at {}:{}:{}", l.file(), l.line(), l.column()),
+        None => String::new(),
+      };
+      let context_id = active_context_id();
+      let root_id = active_root_context_id().and_then(root_id);
+      match root_id {
+        Some(root_id) => log_critical(format_args!(
+          "[root_id={} context_id={}] panicked{}: {}",
+          root_id, context_id, location, message
+        )),
+        None => log_critical(format_args!(
+          "[context_id={}] panicked{}: {}",
+          context_id, location, message
+        )),
+      }
+    }));
+  }
+
+  /// Synchronizes the `log` max level with the level of the host. Called on VM start,
+  /// configure and tick.
+  pub fn refresh_level() {
+    let mut level: u32 = 0;
+    let code = unsafe { proxy_get_log_level(&mut level) };
+    match WasmResult::try_from(code) {
+      Ok(WasmResult::Ok) => {}
+      _ => return,
+    }
+    let filter = match LogLevel::try_from(level) {
+      Ok(l) => Logger::level_filter(l),
+      Err(_) => log::LevelFilter::Off,
+    };
+    HOST_LOG_LEVEL.store(filter as usize, Ordering::Relaxed);
+    log::set_max_level(filter);
+  }
+
+  /// Restricts the level of the plugin owning `root_context_id`. The host level still applies,
+  /// so the override can only make logging less verbose.
+  pub fn set_root_context_level(root_context_id: u32, level: Option<log::LevelFilter>) {
+    let mut levels = ROOT_CONTEXT_LOG_LEVELS.lock().unwrap();
     match level {
-      log::Level::Trace => 0,
-      log::Level::Debug => 1,
-      log::Level::Info => 2,
-      log::Level::Warn => 3,
-      log::Level::Error => 4,
+      Some(l) => levels.insert(root_context_id, l),
+      None => levels.remove(&root_context_id),
+    };
+  }
+
+  /// Parses level names used in plugin configurations: `trace`, `debug`, `info`, `warn`,
+  /// `error`, `critical` and `off`. `critical` disables every `log` record and leaves only
+  /// `critical!`, like the host level of the same name.
+  pub fn parse_level(level: &str) -> Option<log::LevelFilter> {
+    match level.to_ascii_lowercase().as_str() {
+      "trace" => Some(log::LevelFilter::Trace),
+      "debug" => Some(log::LevelFilter::Debug),
+      "info" => Some(log::LevelFilter::Info),
+      "warn" | "warning" => Some(log::LevelFilter::Warn),
+      "error" => Some(log::LevelFilter::Error),
+      "critical" | "off" => Some(log::LevelFilter::Off),
+      _ => None,
+    }
+  }
+
+  /// Selects how records are rendered. Defaults to `LogFormat::Plain`, which keeps the output
+  /// of plugins that don't opt in unchanged.
+  pub fn set_format(format: LogFormat) {
+    LOG_FORMAT.store(format as usize, Ordering::Relaxed);
+  }
+
+  pub fn format() -> LogFormat {
+    match LOG_FORMAT.load(Ordering::Relaxed) {
+      0 => LogFormat::Plain,
+      1 => LogFormat::Prefixed,
+      _ => LogFormat::Json,
     }
   }
+
+  /// Tags records emitted from stream contexts with the `x-request-id` request header.
+  pub fn set_include_request_id(include: bool) {
+    INCLUDE_REQUEST_ID.store(include, Ordering::Relaxed);
+  }
+
+  /// Tags records with the host time in RFC 3339. Envoy already timestamps its log lines, so
+  /// this is mostly useful with `LogFormat::Json`.
+  pub fn set_include_timestamp(include: bool) {
+    INCLUDE_TIMESTAMP.store(include, Ordering::Relaxed);
+  }
+
+  fn format_record(record: &log::Record) -> String {
+    let mut tags: Vec<(&str, String)> = Vec::new();
+    if INCLUDE_TIMESTAMP.load(Ordering::Relaxed) {
+      tags.push(("timestamp", format_rfc3339(current_time())));
+    }
+    let context_id = active_context_id();
+    let root_context_id = active_root_context_id();
+    if let Some(root_context_id) = root_context_id {
+      if let Some(root_id) = root_id(root_context_id) {
+        tags.push(("root_id", root_id));
+      }
+    }
+    if context_id != 0 {
+      tags.push(("context_id", context_id.to_string()));
+    }
+    if INCLUDE_REQUEST_ID.load(Ordering::Relaxed)
+      && root_context_id.is_some()
+      && root_context_id != Some(context_id)
+    {
+      if let Ok(request_id) = get_request_header("x-request-id".to_string()) {
+        let request_id = request_id.to_string();
+        if !request_id.is_empty() {
+          tags.push(("request_id", request_id));
+        }
+      }
+    }
+    let mut key_values = KeyValueCollector(Vec::new());
+    let _ = record.key_values().visit(&mut key_values);
+    let message = record.args().to_string();
+
+    match Logger::format() {
+      LogFormat::Plain | LogFormat::Prefixed => {
+        let mut line = String::new();
+        if Logger::format() == LogFormat::Prefixed && !tags.is_empty() {
+          let prefix = tags
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<String>>()
+            .join(" ");
+          line.push_str(&format!("[{}] ", prefix));
+        }
+        line.push_str(&message);
+        for (k, v) in key_values.0 {
+          line.push_str(&format!(" {}={}", k, v));
+        }
+        line
+      }
+      LogFormat::Json => {
+        let mut fields = vec![
+          ("level", record.level().as_str().to_ascii_lowercase()),
+          ("target", record.target().to_string()),
+        ];
+        fields.extend(tags);
+        fields.push(("message", message));
+        let mut json = fields
+          .iter()
+          .map(|(k, v)| format!("\"{}\":\"{}\"", k, json_escape(v)))
+          .collect::<Vec<String>>();
+        for (k, v) in key_values.0 {
+          json.push(format!("\"{}\":\"{}\"", json_escape(&k), json_escape(&v)));
+        }
+        format!("{{{}}}", json.join(","))
+      }
+    }
+  }
+
+  // `log` has no level above `Error`, so a host at `critical` filters out every `log` record;
+  // `critical!` goes straight to `proxy_log` and is left to the host.
+  fn level_filter(level: LogLevel) -> log::LevelFilter {
+    match level {
+      LogLevel::Trace => log::LevelFilter::Trace,
+      LogLevel::Debug => log::LevelFilter::Debug,
+      LogLevel::Info => log::LevelFilter::Info,
+      LogLevel::Warn => log::LevelFilter::Warn,
+      LogLevel::Error => log::LevelFilter::Error,
+      LogLevel::Critical => log::LevelFilter::Off,
+    }
+  }
+
+  fn host_level() -> log::LevelFilter {
+    match HOST_LOG_LEVEL.load(Ordering::Relaxed) {
+      0 => log::LevelFilter::Off,
+      1 => log::LevelFilter::Error,
+      2 => log::LevelFilter::Warn,
+      3 => log::LevelFilter::Info,
+      4 => log::LevelFilter::Debug,
+      _ => log::LevelFilter::Trace,
+    }
+  }
+
+  fn effective_level() -> log::LevelFilter {
+    let host_level = Logger::host_level();
+    let root_context_id = match active_root_context_id() {
+      Some(id) => id,
+      None => return host_level,
+    };
+    match ROOT_CONTEXT_LOG_LEVELS.try_lock() {
+      Ok(levels) => match levels.get(&root_context_id) {
+        Some(l) => std::cmp::min(*l, host_level),
+        None => host_level,
+      },
+      Err(_) => host_level,
+    }
+  }
+
+  fn proxywasm_loglevel(level: log::Level) -> u32 {
+    log_level_to_int(match level {
+      log::Level::Trace => LogLevel::Trace,
+      log::Level::Debug => LogLevel::Debug,
+      log::Level::Info => LogLevel::Info,
+      log::Level::Warn => LogLevel::Warn,
+      log::Level::Error => LogLevel::Error,
+    })
+  }
 }
 
 impl log::Log for Logger {
-  fn enabled(&self, _metadata: &log::Metadata) -> bool {
-    true
+  fn enabled(&self, metadata: &log::Metadata) -> bool {
+    metadata.level() <= Logger::effective_level()
   }
 
   fn log(&self, record: &log::Record) {
+    if !self.enabled(record.metadata()) {
+      return;
+    }
     let level = Logger::proxywasm_loglevel(record.level());
-    let message = record.args().to_string();
+    let message = Logger::format_record(record);
     unsafe {
       proxy_log(level, message.as_ptr(), message.len());
     }
@@ -36,3 +295,47 @@ impl log::Log for Logger {
 
   fn flush(&self) {}
 }
+
+struct KeyValueCollector(Vec<(String, String)>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for KeyValueCollector {
+  fn visit_pair(
+    &mut self,
+    key: log::kv::Key<'kvs>,
+    value: log::kv::Value<'kvs>,
+  ) -> Result<(), log::kv::Error> {
+    self.0.push((key.to_string(), value.to_string()));
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn maps_host_levels_to_filters() {
+    let levels = [
+      (0, log::LevelFilter::Trace),
+      (1, log::LevelFilter::Debug),
+      (2, log::LevelFilter::Info),
+      (3, log::LevelFilter::Warn),
+      (4, log::LevelFilter::Error),
+      (5, log::LevelFilter::Off),
+    ];
+    for (level, filter) in levels.iter() {
+      let level = LogLevel::try_from(*level).unwrap();
+      assert_eq!(Logger::level_filter(level), *filter);
+    }
+    assert!(log::Level::Error > Logger::level_filter(LogLevel::Critical));
+  }
+
+  #[test]
+  fn parses_configured_levels() {
+    assert_eq!(Logger::parse_level("WARNING"), Some(log::LevelFilter::Warn));
+    assert_eq!(Logger::parse_level("error"), Some(log::LevelFilter::Error));
+    assert_eq!(Logger::parse_level("critical"), Some(log::LevelFilter::Off));
+    assert_eq!(Logger::parse_level("off"), Some(log::LevelFilter::Off));
+    assert_eq!(Logger::parse_level("verbose"), None);
+  }
+}
@@ -0,0 +1,260 @@
+use crate::context::*;
+use crate::host::*;
+use crate::reply::local_reply_count;
+use crate::time::current_time_nanos;
+use crate::types::*;
+use lazy_static::lazy_static;
+use log::warn;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll, Wake, Waker};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreamDirection {
+  Request,
+  Response,
+}
+
+type TaskFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+struct Task {
+  context_id: u32,
+  future: TaskFuture,
+  resume: Option<StreamDirection>,
+  // Set once the task sent a local reply, after which the stream must not be resumed.
+  replied: bool,
+}
+
+/// State of a callout awaited by a future, keyed by the callout token.
+pub(crate) enum CallState<T> {
+  Pending(Option<Waker>),
+  Complete(T),
+}
+
+lazy_static! {
+  static ref TASKS: Mutex<HashMap<u64, Task>> = Mutex::new(HashMap::new());
+  static ref READY_TASKS: Mutex<VecDeque<u64>> = Mutex::new(VecDeque::new());
+  // Pending `Sleep` futures by timer id, with their deadline.
+  static ref TIMERS: Mutex<HashMap<u64, (u64, Waker)>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(1);
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(1);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+struct TaskWaker {
+  task_id: u64,
+}
+
+impl Wake for TaskWaker {
+  fn wake(self: Arc<Self>) {
+    READY_TASKS.lock().unwrap().push_back(self.task_id);
+  }
+}
+
+fn spawn_task<F>(future: F) -> u64
+where
+  F: Future<Output = ()> + Send + 'static,
+{
+  let task_id = NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed);
+  TASKS.lock().unwrap().insert(
+    task_id,
+    Task {
+      context_id: active_context_id(),
+      future: Box::pin(future),
+      resume: None,
+      replied: false,
+    },
+  );
+  READY_TASKS.lock().unwrap().push_back(task_id);
+  run_ready_tasks();
+  task_id
+}
+
+/// Runs `future` on behalf of the active context. It is polled right away and then whenever a
+/// callout, gRPC call or timer it awaits makes progress.
+pub fn spawn<F>(future: F)
+where
+  F: Future<Output = ()> + Send + 'static,
+{
+  spawn_task(future);
+}
+
+/// Spawns `future` and returns whether the stream has to be paused for it. If the future does
+/// not complete immediately, `direction` of the stream is resumed once it does, unless the
+/// future sent a local reply. A future which sent a local reply while completing immediately
+/// keeps the stream paused as well.
+pub fn spawn_and_pause<F>(direction: StreamDirection, future: F) -> bool
+where
+  F: Future<Output = ()> + Send + 'static,
+{
+  let replies = local_reply_count();
+  let task_id = spawn_task(future);
+  match TASKS.lock().unwrap().get_mut(&task_id) {
+    Some(task) => {
+      task.resume = Some(direction);
+      true
+    }
+    None => local_reply_count() != replies,
+  }
+}
+
+/// Shorthand of `spawn_and_pause` for `on_request_headers`.
+pub fn spawn_request<F>(future: F) -> FilterHeadersStatus
+where
+  F: Future<Output = ()> + Send + 'static,
+{
+  match spawn_and_pause(StreamDirection::Request, future) {
+    true => FilterHeadersStatus::StopIteration,
+    false => FilterHeadersStatus::Continue,
+  }
+}
+
+/// Shorthand of `spawn_and_pause` for `on_response_headers`.
+pub fn spawn_response<F>(future: F) -> FilterHeadersStatus
+where
+  F: Future<Output = ()> + Send + 'static,
+{
+  match spawn_and_pause(StreamDirection::Response, future) {
+    true => FilterHeadersStatus::StopIteration,
+    false => FilterHeadersStatus::Continue,
+  }
+}
+
+fn switch_context(context_id: u32) {
+  if context_id == active_context_id() {
+    return;
+  }
+  unsafe {
+    proxy_set_effective_context(context_id);
+  }
+  set_active_context(context_id);
+}
+
+fn resume_stream(direction: StreamDirection) {
+  let code = unsafe {
+    match direction {
+      StreamDirection::Request => proxy_continue_request(),
+      StreamDirection::Response => proxy_continue_response(),
+    }
+  };
+  match WasmResult::try_from(code) {
+    Ok(WasmResult::Ok) => {}
+    Ok(r) => warn!("failed to resume {:?}: {}", direction, r),
+    Err(e) => warn!("failed to convert: {}", e),
+  }
+}
+
+/// Polls every woken task. Called by the host callbacks which may wake tasks.
+pub(crate) fn run_ready_tasks() {
+  // Tasks spawned while a task is polled are picked up by the outer loop.
+  if RUNNING.swap(true, Ordering::Relaxed) {
+    return;
+  }
+  let active_context_id = active_context_id();
+  loop {
+    let task_id = match READY_TASKS.lock().unwrap().pop_front() {
+      Some(id) => id,
+      None => break,
+    };
+    let mut task = match TASKS.lock().unwrap().remove(&task_id) {
+      Some(task) => task,
+      None => continue,
+    };
+    switch_context(task.context_id);
+    let waker = Waker::from(Arc::new(TaskWaker { task_id }));
+    let mut cx = TaskContext::from_waker(&waker);
+    let replies = local_reply_count();
+    let poll = task.future.as_mut().poll(&mut cx);
+    task.replied |= local_reply_count() != replies;
+    match poll {
+      Poll::Pending => {
+        TASKS.lock().unwrap().insert(task_id, task);
+      }
+      Poll::Ready(()) => {
+        if let (Some(direction), false) = (task.resume, task.replied) {
+          resume_stream(direction);
+        }
+      }
+    }
+  }
+  switch_context(active_context_id);
+  RUNNING.store(false, Ordering::Relaxed);
+}
+
+/// Drops the tasks of a finished context. gRPC calls they await are cancelled on the host;
+/// HTTP calls, which the ABI cannot cancel, run to completion and their responses are ignored.
+pub(crate) fn cancel_context_tasks(context_id: u32) {
+  let cancelled: Vec<Task> = {
+    let mut tasks = TASKS.lock().unwrap();
+    let task_ids: Vec<u64> = tasks
+      .iter()
+      .filter(|(_, t)| t.context_id == context_id)
+      .map(|(id, _)| *id)
+      .collect();
+    task_ids.iter().filter_map(|id| tasks.remove(id)).collect()
+  };
+  drop(cancelled);
+}
+
+/// Future completing after `duration`. Timers are checked on `on_tick`, so their resolution is
+/// the tick period of the root context, see `set_tick_period`.
+pub fn sleep(duration: Duration) -> Sleep {
+  Sleep {
+    deadline: current_time_nanos().saturating_add(duration.as_nanos() as u64),
+    timer_id: NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed),
+  }
+}
+
+pub struct Sleep {
+  deadline: u64,
+  timer_id: u64,
+}
+
+impl Future for Sleep {
+  type Output = ();
+  fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<()> {
+    let mut timers = TIMERS.lock().unwrap();
+    if current_time_nanos() >= self.deadline {
+      timers.remove(&self.timer_id);
+      return Poll::Ready(());
+    }
+    match timers.get_mut(&self.timer_id) {
+      Some((_, waker)) if waker.will_wake(cx.waker()) => {}
+      Some((_, waker)) => *waker = cx.waker().clone(),
+      None => {
+        timers.insert(self.timer_id, (self.deadline, cx.waker().clone()));
+      }
+    }
+    Poll::Pending
+  }
+}
+
+impl Drop for Sleep {
+  fn drop(&mut self) {
+    TIMERS.lock().unwrap().remove(&self.timer_id);
+  }
+}
+
+pub(crate) fn wake_expired_timers() {
+  let now = current_time_nanos();
+  let expired: Vec<Waker> = {
+    let mut timers = TIMERS.lock().unwrap();
+    let mut expired = Vec::new();
+    timers.retain(|_, (deadline, waker)| {
+      if *deadline <= now {
+        expired.push(waker.clone());
+      }
+      *deadline > now
+    });
+    expired
+  };
+  for waker in expired {
+    waker.wake();
+  }
+}
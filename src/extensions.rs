@@ -0,0 +1,94 @@
+use crate::context::{active_context_id, context_extensions};
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Type-keyed map holding at most one value per type.
+#[derive(Default)]
+pub struct Extensions {
+  map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+  pub fn new() -> Extensions {
+    Extensions {
+      map: HashMap::new(),
+    }
+  }
+
+  /// Inserts a value and returns the previous value of the same type.
+  pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+    self
+      .map
+      .insert(TypeId::of::<T>(), Box::new(value))
+      .and_then(|v| v.downcast().ok().map(|v| *v))
+  }
+
+  pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+    self
+      .map
+      .get(&TypeId::of::<T>())
+      .and_then(|v| v.downcast_ref())
+  }
+
+  pub fn get_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+    self
+      .map
+      .get_mut(&TypeId::of::<T>())
+      .and_then(|v| v.downcast_mut())
+  }
+
+  pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+    self
+      .map
+      .remove(&TypeId::of::<T>())
+      .and_then(|v| v.downcast().ok().map(|v| *v))
+  }
+
+  pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+    self.map.contains_key(&TypeId::of::<T>())
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.map.is_empty()
+  }
+
+  pub fn clear(&mut self) {
+    self.map.clear()
+  }
+}
+
+// ====================== Request-Scoped Extensions API =============================
+// Each context owns an `Extensions` which lives until the context is deleted. The functions
+// below use the one of the context whose callback is running.
+
+pub fn extensions() -> Arc<Mutex<Extensions>> {
+  context_extensions(active_context_id())
+}
+
+pub fn insert_extension<T: Any + Send + Sync>(value: T) -> Option<T> {
+  extensions().lock().unwrap().insert(value)
+}
+
+/// Returns a copy of the value of type `T`, see `with_extension` to borrow it instead.
+pub fn get_extension<T: Any + Send + Sync + Clone>() -> Option<T> {
+  extensions().lock().unwrap().get::<T>().cloned()
+}
+
+/// Calls `f` with the value of type `T`, if any. Extensions are locked during the call, so `f`
+/// must not use the functions of this module.
+pub fn with_extension<T, R, F>(f: F) -> Option<R>
+where
+  T: Any + Send + Sync,
+  F: FnOnce(&mut T) -> R,
+{
+  extensions().lock().unwrap().get_mut::<T>().map(f)
+}
+
+pub fn remove_extension<T: Any + Send + Sync>() -> Option<T> {
+  extensions().lock().unwrap().remove::<T>()
+}
+
+pub fn contains_extension<T: Any + Send + Sync>() -> bool {
+  extensions().lock().unwrap().contains::<T>()
+}
@@ -0,0 +1,226 @@
+use crate::context::*;
+use crate::extensions::{extensions, Extensions};
+use crate::reply::local_reply_count;
+use crate::types::*;
+use std::sync::{Arc, Mutex};
+
+type Stage = Arc<dyn Context + Sync + Send>;
+type StageFactory =
+  Box<dyn Fn(Arc<dyn RootContext + Sync + Send>, Arc<Mutex<Extensions>>) -> Stage + Sync + Send>;
+
+/// Context running several contexts as stages of one filter. Request callbacks run the stages in
+/// order and response callbacks in reverse order. A stage which stops iteration or sends a local
+/// reply ends the callback: the remaining stages are not run for it.
+pub struct FilterChain {
+  stages: Vec<Stage>,
+  extensions: Arc<Mutex<Extensions>>,
+}
+
+impl FilterChain {
+  /// Creates a chain sharing the extensions of the active context, which is the context being
+  /// created when called from a context factory.
+  pub fn new(stages: Vec<Stage>) -> FilterChain {
+    FilterChain::with_extensions(stages, extensions())
+  }
+
+  pub fn with_extensions(stages: Vec<Stage>, extensions: Arc<Mutex<Extensions>>) -> FilterChain {
+    FilterChain { stages, extensions }
+  }
+
+  /// Values shared by the stages for the lifetime of the stream.
+  pub fn extensions(&self) -> Arc<Mutex<Extensions>> {
+    self.extensions.clone()
+  }
+
+  fn run<'a, I, T, F>(stages: I, continued: T, is_continue: fn(&T) -> bool, mut call: F) -> T
+  where
+    I: Iterator<Item = &'a Stage>,
+    F: FnMut(&Stage) -> T,
+  {
+    for stage in stages {
+      let replies = local_reply_count();
+      let status = call(stage);
+      if !is_continue(&status) || local_reply_count() != replies {
+        return status;
+      }
+    }
+    continued
+  }
+}
+
+fn filter_continue(s: &FilterStatus) -> bool {
+  matches!(s, FilterStatus::Continue)
+}
+
+fn headers_continue(s: &FilterHeadersStatus) -> bool {
+  matches!(s, FilterHeadersStatus::Continue)
+}
+
+fn metadata_continue(s: &FilterMetadataStatus) -> bool {
+  matches!(s, FilterMetadataStatus::Continue)
+}
+
+fn trailers_continue(s: &FilterTrailersStatus) -> bool {
+  matches!(s, FilterTrailersStatus::Continue)
+}
+
+fn data_continue(s: &FilterDataStatus) -> bool {
+  matches!(s, FilterDataStatus::Continue)
+}
+
+impl Context for FilterChain {
+  fn on_create(&self) {
+    for stage in &self.stages {
+      stage.on_create();
+    }
+  }
+  fn on_new_connection(&self) -> FilterStatus {
+    FilterChain::run(
+      self.stages.iter(),
+      FilterStatus::Continue,
+      filter_continue,
+      |s| s.on_new_connection(),
+    )
+  }
+  fn on_downstream_connection(&self, _data_length: usize, _is_stream_end: bool) -> FilterStatus {
+    FilterChain::run(
+      self.stages.iter(),
+      FilterStatus::Continue,
+      filter_continue,
+      |s| s.on_downstream_connection(_data_length, _is_stream_end),
+    )
+  }
+  fn on_request_headers(&self, _headers: u32) -> FilterHeadersStatus {
+    FilterChain::run(
+      self.stages.iter(),
+      FilterHeadersStatus::Continue,
+      headers_continue,
+      |s| s.on_request_headers(_headers),
+    )
+  }
+  fn on_request_metadata(&self, _element: u32) -> FilterMetadataStatus {
+    FilterChain::run(
+      self.stages.iter(),
+      FilterMetadataStatus::Continue,
+      metadata_continue,
+      |s| s.on_request_metadata(_element),
+    )
+  }
+  fn on_request_trailers(&self, _trailers: u32) -> FilterTrailersStatus {
+    FilterChain::run(
+      self.stages.iter(),
+      FilterTrailersStatus::Continue,
+      trailers_continue,
+      |s| s.on_request_trailers(_trailers),
+    )
+  }
+  fn on_request_body(&self, _body_buffer_length: usize, _is_stream_end: bool) -> FilterDataStatus {
+    FilterChain::run(
+      self.stages.iter(),
+      FilterDataStatus::Continue,
+      data_continue,
+      |s| s.on_request_body(_body_buffer_length, _is_stream_end),
+    )
+  }
+  fn on_response_headers(&self, _headers: u32) -> FilterHeadersStatus {
+    FilterChain::run(
+      self.stages.iter().rev(),
+      FilterHeadersStatus::Continue,
+      headers_continue,
+      |s| s.on_response_headers(_headers),
+    )
+  }
+  fn on_response_metadata(&self, _element: u32) -> FilterMetadataStatus {
+    FilterChain::run(
+      self.stages.iter().rev(),
+      FilterMetadataStatus::Continue,
+      metadata_continue,
+      |s| s.on_response_metadata(_element),
+    )
+  }
+  fn on_response_trailers(&self, _trailers: u32) -> FilterTrailersStatus {
+    FilterChain::run(
+      self.stages.iter().rev(),
+      FilterTrailersStatus::Continue,
+      trailers_continue,
+      |s| s.on_response_trailers(_trailers),
+    )
+  }
+  fn on_response_body(&self, _body_buffer_length: usize, _is_stream_end: bool) -> FilterDataStatus {
+    FilterChain::run(
+      self.stages.iter().rev(),
+      FilterDataStatus::Continue,
+      data_continue,
+      |s| s.on_response_body(_body_buffer_length, _is_stream_end),
+    )
+  }
+  // Tokens are only known to the stage which dispatched the call, so every stage sees the
+  // response and ignores tokens it does not own.
+  fn on_http_call_response(
+    &self,
+    _token: u32,
+    _num_headers: u32,
+    _body_size: usize,
+    _num_trailers: u32,
+  ) {
+    for stage in &self.stages {
+      stage.on_http_call_response(_token, _num_headers, _body_size, _num_trailers);
+    }
+  }
+  fn on_grpc_receive(&self, _token: u32, _response_size: usize) {
+    for stage in &self.stages {
+      stage.on_grpc_receive(_token, _response_size);
+    }
+  }
+  fn on_grpc_close(&self, _token: u32, _status_code: GrpcStatus) {
+    for stage in &self.stages {
+      stage.on_grpc_close(_token, _status_code);
+    }
+  }
+  fn on_log(&self) {
+    for stage in &self.stages {
+      stage.on_log();
+    }
+  }
+}
+
+/// Context factory building a `FilterChain` per stream. Each stage factory receives the root
+/// context and the extensions of the stream, which are also reachable through the functions of
+/// the `extensions` module.
+#[derive(Default)]
+pub struct FilterChainFactory {
+  stages: Vec<StageFactory>,
+}
+
+impl FilterChainFactory {
+  pub fn new() -> FilterChainFactory {
+    FilterChainFactory { stages: Vec::new() }
+  }
+
+  /// Appends a stage. Stages run in the order they are added.
+  pub fn stage<F>(mut self, factory: F) -> FilterChainFactory
+  where
+    F: Fn(Arc<dyn RootContext + Sync + Send>, Arc<Mutex<Extensions>>) -> Stage
+      + Sync
+      + Send
+      + 'static,
+  {
+    self.stages.push(Box::new(factory));
+    self
+  }
+}
+
+impl ContextFactory for FilterChainFactory {
+  fn create(
+    &self,
+    _root_context: Arc<dyn RootContext + Sync + Send>,
+  ) -> Arc<dyn Context + Sync + Send> {
+    let extensions = extensions();
+    let stages = self
+      .stages
+      .iter()
+      .map(|f| f(_root_context.clone(), extensions.clone()))
+      .collect();
+    Arc::new(FilterChain::with_extensions(stages, extensions))
+  }
+}
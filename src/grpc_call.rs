@@ -0,0 +1,199 @@
+use crate::encoding::encode_varint;
+use crate::executor::CallState;
+use crate::host::*;
+use crate::payload_wrapper::*;
+use crate::types::*;
+use lazy_static::lazy_static;
+use log::warn;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::os::raw::c_char;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+struct GrpcCall {
+  state: CallState<Result<Vec<u8>, GrpcStatus>>,
+  message: Option<Vec<u8>>,
+}
+
+lazy_static! {
+  static ref GRPC_CALLS: Mutex<HashMap<u32, GrpcCall>> = Mutex::new(HashMap::new());
+}
+
+// Serialized `envoy.config.core.v3.GrpcService` with `envoy_grpc.cluster_name` set.
+fn envoy_grpc_service(cluster: &str) -> Vec<u8> {
+  let mut envoy_grpc = vec![0x0a];
+  encode_varint(cluster.len() as u64, &mut envoy_grpc);
+  envoy_grpc.extend_from_slice(cluster.as_bytes());
+  let mut service = vec![0x0a];
+  encode_varint(envoy_grpc.len() as u64, &mut service);
+  service.extend_from_slice(&envoy_grpc);
+  service
+}
+
+/// Calls `service_name/method_name` on `cluster` with a serialized request message and returns
+/// the token identifying the call. Results are delivered to `on_grpc_receive` and
+/// `on_grpc_close` of the calling context. Calls from stream contexts carry the trace context of
+/// the request in their initial metadata.
+pub fn dispatch_grpc_call(
+  cluster: &str,
+  service_name: &str,
+  method_name: &str,
+  message: &[u8],
+  timeout: Duration,
+) -> Result<u32, String> {
+  let service = envoy_grpc_service(cluster);
+  let mut token: u32 = 0;
+  unsafe {
+    let code = proxy_grpc_call(
+      service.as_ptr() as *const c_char,
+      service.len(),
+      service_name.as_ptr() as *const c_char,
+      service_name.len(),
+      method_name.as_ptr() as *const c_char,
+      method_name.len(),
+      message.as_ptr() as *const c_char,
+      message.len(),
+      timeout.as_millis() as u32,
+      &mut token,
+    );
+    match WasmResult::try_from(code) {
+      Ok(WasmResult::Ok) => Ok(token),
+      Ok(r) => Err(r.to_string()),
+      Err(e) => Err(e),
+    }
+  }
+}
+
+pub fn cancel_grpc_call(token: u32) -> WasmResult {
+  GRPC_CALLS.lock().unwrap().remove(&token);
+  to_wasm_result(unsafe { proxy_grpc_cancel(token) })
+}
+
+/// Same as `dispatch_grpc_call`, but the serialized response message is awaited. Resolves to
+/// the status of the call if it did not succeed.
+pub fn grpc_call(
+  cluster: &str,
+  service_name: &str,
+  method_name: &str,
+  message: &[u8],
+  timeout: Duration,
+) -> GrpcCallFuture {
+  let token = dispatch_grpc_call(cluster, service_name, method_name, message, timeout);
+  match token {
+    Ok(token) => {
+      GRPC_CALLS.lock().unwrap().insert(
+        token,
+        GrpcCall {
+          state: CallState::Pending(None),
+          message: None,
+        },
+      );
+      GrpcCallFuture { token: Some(token) }
+    }
+    Err(e) => {
+      warn!("failed to dispatch grpc call: {}", e);
+      GrpcCallFuture { token: None }
+    }
+  }
+}
+
+pub struct GrpcCallFuture {
+  token: Option<u32>,
+}
+
+impl Future for GrpcCallFuture {
+  type Output = Result<Vec<u8>, GrpcStatus>;
+  fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+    let token = match self.token {
+      Some(token) => token,
+      None => return Poll::Ready(Err(GrpcStatus::Internal)),
+    };
+    let mut calls = GRPC_CALLS.lock().unwrap();
+    let call = match calls.get_mut(&token) {
+      Some(call) => call,
+      None => return Poll::Ready(Err(GrpcStatus::Canceled)),
+    };
+    if let CallState::Pending(waker) = &mut call.state {
+      *waker = Some(cx.waker().clone());
+      return Poll::Pending;
+    }
+    match calls.remove(&token) {
+      Some(GrpcCall {
+        state: CallState::Complete(result),
+        ..
+      }) => Poll::Ready(result),
+      _ => Poll::Ready(Err(GrpcStatus::Internal)),
+    }
+  }
+}
+
+// A call dropped before completion, e.g. along with the tasks of a finished context, is
+// cancelled on the host so that it doesn't keep running for nobody.
+impl Drop for GrpcCallFuture {
+  fn drop(&mut self) {
+    let token = match self.token {
+      Some(token) => token,
+      None => return,
+    };
+    let call = GRPC_CALLS.lock().unwrap().remove(&token);
+    if let Some(GrpcCall {
+      state: CallState::Pending(_),
+      ..
+    }) = call
+    {
+      match to_wasm_result(unsafe { proxy_grpc_cancel(token) }) {
+        WasmResult::Ok => {}
+        r => warn!("failed to cancel grpc call {}: {}", token, r),
+      }
+    }
+  }
+}
+
+/// Stores the response message for the future awaiting `token`. Returns false if the call is
+/// not awaited.
+pub(crate) fn receive_grpc_message(token: u32, response_size: usize) -> bool {
+  if !GRPC_CALLS.lock().unwrap().contains_key(&token) {
+    return false;
+  }
+  let message = match response_size {
+    0 => Ok(Vec::new()),
+    _ => get_buffer_bytes(BufferType::GrpcReceiveBuffer, 0, response_size),
+  };
+  match message {
+    Ok(message) => {
+      if let Some(call) = GRPC_CALLS.lock().unwrap().get_mut(&token) {
+        call.message = Some(message);
+      }
+    }
+    Err(e) => warn!("failed to read grpc response: {}", e),
+  }
+  true
+}
+
+/// Completes the future awaiting `token`. Returns false if the call is not awaited.
+pub(crate) fn close_grpc_call(token: u32, status: GrpcStatus) -> bool {
+  let waker = {
+    let mut calls = GRPC_CALLS.lock().unwrap();
+    let call = match calls.get_mut(&token) {
+      Some(call) => call,
+      None => return false,
+    };
+    let result = match (status, call.message.take()) {
+      (GrpcStatus::Ok, Some(message)) => Ok(message),
+      (GrpcStatus::Ok, None) => Err(GrpcStatus::Internal),
+      (status, _) => Err(status),
+    };
+    match std::mem::replace(&mut call.state, CallState::Complete(result)) {
+      CallState::Pending(waker) => waker,
+      CallState::Complete(_) => None,
+    }
+  };
+  if let Some(waker) = waker {
+    waker.wake();
+  }
+  true
+}
@@ -0,0 +1,280 @@
+use crate::body::{BodyHandler, BodyPolicy};
+use crate::executor::StreamDirection;
+use crate::types::*;
+use log::warn;
+use std::sync::Mutex;
+
+// Compressed flag followed by the big-endian message length.
+const FRAME_HEADER_SIZE: usize = 5;
+
+/// Length-prefixed gRPC message as found in HTTP/2 bodies.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrpcFrame {
+  pub compressed: bool,
+  pub message: Vec<u8>,
+}
+
+impl GrpcFrame {
+  pub fn new(message: Vec<u8>) -> GrpcFrame {
+    GrpcFrame {
+      compressed: false,
+      message,
+    }
+  }
+
+  pub fn encoded_len(&self) -> usize {
+    FRAME_HEADER_SIZE + self.message.len()
+  }
+
+  pub fn encode_to(&self, buffer: &mut Vec<u8>) {
+    buffer.push(self.compressed as u8);
+    buffer.extend_from_slice(&(self.message.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(&self.message);
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(self.encoded_len());
+    self.encode_to(&mut buffer);
+    buffer
+  }
+
+  #[cfg(feature = "prost")]
+  pub fn from_message<M: prost::Message>(message: &M) -> GrpcFrame {
+    GrpcFrame::new(message.encode_to_vec())
+  }
+
+  /// Decodes the message as `M`. Compressed messages have to be decompressed first.
+  #[cfg(feature = "prost")]
+  pub fn decode_message<M: prost::Message + Default>(&self) -> Result<M, String> {
+    if self.compressed {
+      return Err("grpc message is compressed".to_string());
+    }
+    M::decode(self.message.as_slice()).map_err(|e| e.to_string())
+  }
+}
+
+pub fn encode_frames(frames: &[GrpcFrame]) -> Vec<u8> {
+  let mut buffer = Vec::with_capacity(frames.iter().map(|f| f.encoded_len()).sum());
+  for frame in frames {
+    frame.encode_to(&mut buffer);
+  }
+  buffer
+}
+
+/// Reassembles gRPC frames from body chunks. Bytes of an incomplete frame are kept until the
+/// chunks completing it are pushed.
+#[derive(Default)]
+pub struct GrpcFrameDecoder {
+  buffer: Vec<u8>,
+  max_message_size: Option<usize>,
+  failed: bool,
+}
+
+impl GrpcFrameDecoder {
+  pub fn new() -> GrpcFrameDecoder {
+    GrpcFrameDecoder::default()
+  }
+
+  /// Rejects frames announcing a message larger than `size` bytes.
+  pub fn with_max_message_size(size: usize) -> GrpcFrameDecoder {
+    GrpcFrameDecoder {
+      max_message_size: Some(size),
+      ..GrpcFrameDecoder::default()
+    }
+  }
+
+  /// Appends `data` and returns the frames it completes. Once an error is returned, the stream is
+  /// out of sync and every later push fails.
+  pub fn push(&mut self, data: &[u8]) -> Result<Vec<GrpcFrame>, String> {
+    if self.failed {
+      return Err("grpc frame decoder failed earlier".to_string());
+    }
+    self.buffer.extend_from_slice(data);
+    let mut frames = Vec::new();
+    let mut offset = 0;
+    while self.buffer.len() - offset >= FRAME_HEADER_SIZE {
+      let header = &self.buffer[offset..offset + FRAME_HEADER_SIZE];
+      let compressed = match header[0] {
+        0 => false,
+        1 => true,
+        flag => {
+          self.failed = true;
+          return Err(format!("invalid grpc compressed flag: {}", flag));
+        }
+      };
+      let length = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+      if let Some(max) = self.max_message_size {
+        if length > max {
+          self.failed = true;
+          return Err(format!(
+            "grpc message of {} bytes exceeds the limit of {} bytes",
+            length, max
+          ));
+        }
+      }
+      let start = offset + FRAME_HEADER_SIZE;
+      if self.buffer.len() - start < length {
+        break;
+      }
+      frames.push(GrpcFrame {
+        compressed,
+        message: self.buffer[start..start + length].to_vec(),
+      });
+      offset = start + length;
+    }
+    self.buffer.drain(..offset);
+    Ok(frames)
+  }
+
+  /// Number of bytes of the incomplete frame.
+  pub fn buffered(&self) -> usize {
+    self.buffer.len()
+  }
+
+  /// Returns the bytes of the incomplete frame and resets the decoder.
+  pub fn take_buffered(&mut self) -> Vec<u8> {
+    std::mem::take(&mut self.buffer)
+  }
+}
+
+/// Rewrites the gRPC messages of a request or response body in flight.
+///
+/// Every chunk is replaced by the frames it completes, so a frame split across chunks is
+/// forwarded with the chunk carrying its end. Resized messages change the body length, so
+/// `content-length`, when sent at all, has to be removed from the headers.
+pub struct GrpcBody {
+  body: BodyHandler,
+  decoder: Mutex<GrpcFrameDecoder>,
+}
+
+impl GrpcBody {
+  pub fn new(direction: StreamDirection, decoder: GrpcFrameDecoder) -> GrpcBody {
+    GrpcBody {
+      body: BodyHandler::new(direction, BodyPolicy::Chunked),
+      decoder: Mutex::new(decoder),
+    }
+  }
+
+  pub fn request() -> GrpcBody {
+    GrpcBody::new(StreamDirection::Request, GrpcFrameDecoder::new())
+  }
+
+  pub fn response() -> GrpcBody {
+    GrpcBody::new(StreamDirection::Response, GrpcFrameDecoder::new())
+  }
+
+  pub fn handler(&self) -> &BodyHandler {
+    &self.body
+  }
+
+  /// Handles a body callback. `f` is called for every complete frame, in order, and returns
+  /// whether it modified the frame. Malformed bodies are forwarded untouched.
+  pub fn on_body<F>(
+    &self,
+    body_buffer_length: usize,
+    end_of_stream: bool,
+    mut f: F,
+  ) -> FilterDataStatus
+  where
+    F: FnMut(&mut GrpcFrame) -> bool,
+  {
+    self
+      .body
+      .on_body(body_buffer_length, end_of_stream, |chunk, end_of_stream| {
+        let mut decoder = self.decoder.lock().unwrap();
+        let mut frames = match decoder.push(chunk) {
+          Ok(frames) => frames,
+          Err(e) => {
+            warn!("invalid {:?} grpc body: {}", self.body.direction(), e);
+            // Bytes held back from earlier chunks are released ahead of this one, which is
+            // still in the buffer of the decoder unless it failed earlier.
+            let mut held = decoder.take_buffered();
+            held.truncate(held.len().saturating_sub(chunk.len()));
+            return match held.is_empty() {
+              true => None,
+              false => Some([held.as_slice(), chunk].concat()),
+            };
+          }
+        };
+        let mut modified = false;
+        for frame in frames.iter_mut() {
+          modified |= f(frame);
+        }
+        let mut rewritten = encode_frames(&frames);
+        if end_of_stream && decoder.buffered() != 0 {
+          warn!(
+            "{:?} grpc body ends with an incomplete frame",
+            self.body.direction()
+          );
+          rewritten.extend(decoder.take_buffered());
+        }
+        match modified || rewritten.len() != chunk.len() {
+          true => Some(rewritten),
+          false => None,
+        }
+      })
+  }
+}
+
+/// Returns the `grpc-encoding` of the direction, used to tell how compressed frames are encoded.
+pub fn grpc_encoding(direction: StreamDirection) -> Option<String> {
+  let key = "grpc-encoding".to_string();
+  let value = match direction {
+    StreamDirection::Request => crate::payload::get_request_header(key),
+    StreamDirection::Response => crate::payload::get_response_header(key),
+  };
+  match value.map(|v| v.to_string()) {
+    Ok(v) if !v.is_empty() => Some(v),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn encodes_length_prefixed_frames() {
+    let frame = GrpcFrame {
+      compressed: true,
+      message: b"abc".to_vec(),
+    };
+    assert_eq!(frame.encode(), vec![1, 0, 0, 0, 3, b'a', b'b', b'c']);
+    assert_eq!(frame.encoded_len(), 8);
+    let frames = vec![GrpcFrame::new(Vec::new()), GrpcFrame::new(b"x".to_vec())];
+    assert_eq!(
+      encode_frames(&frames),
+      vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 1, b'x']
+    );
+  }
+
+  #[test]
+  fn reassembles_frames_split_across_chunks() {
+    let frames = vec![
+      GrpcFrame::new(b"hello".to_vec()),
+      GrpcFrame::new(Vec::new()),
+      GrpcFrame::new(vec![7; 300]),
+    ];
+    let encoded = encode_frames(&frames);
+    let mut decoder = GrpcFrameDecoder::new();
+    let mut decoded = Vec::new();
+    for chunk in encoded.chunks(3) {
+      decoded.extend(decoder.push(chunk).unwrap());
+    }
+    assert_eq!(decoded, frames);
+    assert_eq!(decoder.buffered(), 0);
+    assert_eq!(decoder.push(&[0, 0, 0]).unwrap(), Vec::new());
+    assert_eq!(decoder.take_buffered(), vec![0, 0, 0]);
+    assert_eq!(decoder.buffered(), 0);
+  }
+
+  #[test]
+  fn fails_for_good_on_invalid_frames() {
+    let mut decoder = GrpcFrameDecoder::new();
+    assert!(decoder.push(&[2, 0, 0, 0, 0]).is_err());
+    assert!(decoder.push(&GrpcFrame::new(Vec::new()).encode()).is_err());
+    let mut decoder = GrpcFrameDecoder::with_max_message_size(4);
+    assert!(decoder.push(&GrpcFrame::new(vec![0; 4]).encode()).is_ok());
+    assert!(decoder.push(&[0, 0, 0, 0, 5]).is_err());
+  }
+}
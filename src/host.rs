@@ -1,8 +1,16 @@
 use crate::context::*;
+use crate::envoy_log::Logger;
+use crate::executor::*;
+use crate::grpc_call::*;
+use crate::http_call::*;
+use crate::tracing::inject_grpc_initial_metadata;
 use crate::types::*;
+use std::convert::TryFrom;
 use std::os::raw::c_char;
 
-/// Allow host to allocate memory.
+/// Allow host to allocate memory. Only exported to Wasm, where it cannot shadow the allocator of
+/// the C library used by native unit tests.
+#[cfg(target_arch = "wasm32")]
 #[no_mangle]
 fn malloc(size: usize) -> *mut u8 {
   let mut vec: Vec<u8> = Vec::with_capacity(size);
@@ -14,6 +22,7 @@ fn malloc(size: usize) -> *mut u8 {
 }
 
 /// Allow host to free memory.
+#[cfg(target_arch = "wasm32")]
 #[no_mangle]
 fn free(ptr: *mut u8) {
   if !ptr.is_null() {
@@ -25,11 +34,14 @@ fn free(ptr: *mut u8) {
 
 #[no_mangle]
 pub fn proxy_on_vm_start(_root_context_id: u32, _vm_configuration_size: u32) -> u32 {
+  set_active_context(_root_context_id);
+  Logger::refresh_level();
   get_root_context(_root_context_id).on_start(_vm_configuration_size)
 }
 
 #[no_mangle]
 pub fn proxy_on_context_create(_context_id: u32, _parent_context_id: u32) {
+  set_active_context(_context_id);
   if _parent_context_id != 0 {
     ensure_context(_context_id, _parent_context_id).on_create();
   } else {
@@ -39,7 +51,12 @@ pub fn proxy_on_context_create(_context_id: u32, _parent_context_id: u32) {
 
 #[no_mangle]
 pub fn proxy_on_configure(_root_context_id: u32, _vm_configuration_size: u32) -> u32 {
-  match get_root_context(_root_context_id).on_configure(_vm_configuration_size) {
+  set_active_context(_root_context_id);
+  Logger::refresh_level();
+  let root_context = get_root_context(_root_context_id);
+  let configured = root_context.on_configure(_vm_configuration_size);
+  Logger::set_root_context_level(_root_context_id, root_context.log_level());
+  match configured {
     true => 1,
     false => 0,
   }
@@ -47,16 +64,28 @@ pub fn proxy_on_configure(_root_context_id: u32, _vm_configuration_size: u32) ->
 
 #[no_mangle]
 pub fn proxy_on_tick(_root_context_id: u32) {
-  get_root_context(_root_context_id).on_tick()
+  set_active_context(_root_context_id);
+  Logger::refresh_level();
+  get_root_context(_root_context_id).on_tick();
+  wake_expired_timers();
+  run_ready_tasks();
+}
+
+#[no_mangle]
+pub fn proxy_on_queue_ready(_root_context_id: u32, _queue_id: u32) {
+  set_active_context(_root_context_id);
+  get_root_context(_root_context_id).on_queue_ready(_queue_id)
 }
 
 #[no_mangle]
 pub fn proxy_on_new_connection(_context_id: u32) -> u32 {
+  set_active_context(_context_id);
   filter_status_to_int(get_context(_context_id).on_new_connection())
 }
 
 #[no_mangle]
 pub fn proxy_on_downstream_data(_context_id: u32, _data_length: u32, _end_stream: u32) -> u32 {
+  set_active_context(_context_id);
   filter_status_to_int(
     get_context(_context_id).on_downstream_connection(_data_length as usize, _end_stream != 0),
   )
@@ -65,21 +94,25 @@ pub fn proxy_on_downstream_data(_context_id: u32, _data_length: u32, _end_stream
 // ====================== HTTP Request Handling API =============================
 #[no_mangle]
 pub fn proxy_on_request_headers(_context_id: u32, headers: u32) -> u32 {
+  set_active_context(_context_id);
   filter_header_status_to_int(get_context(_context_id).on_request_headers(headers))
 }
 
 #[no_mangle]
 pub fn proxy_on_request_metadata(_context_id: u32, elements: u32) -> u32 {
+  set_active_context(_context_id);
   filter_metadata_status_to_int(get_context(_context_id).on_request_metadata(elements))
 }
 
 #[no_mangle]
 pub fn proxy_on_request_trailers(_context_id: u32, trailers: u32) -> u32 {
+  set_active_context(_context_id);
   filter_trailer_status_to_int(get_context(_context_id).on_request_trailers(trailers))
 }
 
 #[no_mangle]
 pub fn proxy_on_request_body(_context_id: u32, _body_buffer_length: u32, _end_stream: u32) -> u32 {
+  set_active_context(_context_id);
   filter_data_status_to_int(
     get_context(_context_id).on_request_body(_body_buffer_length as usize, _end_stream != 0),
   )
@@ -88,41 +121,210 @@ pub fn proxy_on_request_body(_context_id: u32, _body_buffer_length: u32, _end_st
 // ====================== HTTP Response Handling API =============================
 #[no_mangle]
 pub fn proxy_on_response_headers(_context_id: u32, headers: u32) -> u32 {
+  set_active_context(_context_id);
   filter_header_status_to_int(get_context(_context_id).on_response_headers(headers))
 }
 
 #[no_mangle]
 pub fn proxy_on_response_metadata(_context_id: u32, elements: u32) -> u32 {
+  set_active_context(_context_id);
   filter_metadata_status_to_int(get_context(_context_id).on_response_metadata(elements))
 }
 
 #[no_mangle]
 pub fn proxy_on_response_trailers(_context_id: u32, trailers: u32) -> u32 {
+  set_active_context(_context_id);
   filter_trailer_status_to_int(get_context(_context_id).on_response_trailers(trailers))
 }
 
 #[no_mangle]
 pub fn proxy_on_response_body(_context_id: u32, _body_buffer_length: u32, _end_stream: u32) -> u32 {
+  set_active_context(_context_id);
   filter_data_status_to_int(
     get_context(_context_id).on_response_body(_body_buffer_length as usize, _end_stream != 0),
   )
 }
 
+// ====================== HTTP/gRPC Callout Handling API =============================
+#[no_mangle]
+pub fn proxy_on_http_call_response(
+  _context_id: u32,
+  _token: u32,
+  _headers: u32,
+  _body_size: u32,
+  _trailers: u32,
+) {
+  set_active_context(_context_id);
+  if !complete_http_call(_token, _headers, _body_size as usize) {
+    if is_root_context(_context_id) {
+      get_root_context(_context_id).on_http_call_response(
+        _token,
+        _headers,
+        _body_size as usize,
+        _trailers,
+      );
+    } else {
+      get_context(_context_id).on_http_call_response(
+        _token,
+        _headers,
+        _body_size as usize,
+        _trailers,
+      );
+    }
+  }
+  run_ready_tasks();
+}
+
+#[no_mangle]
+pub fn proxy_on_grpc_create_initial_metadata(_context_id: u32, _token: u32, _headers: u32) {
+  set_active_context(_context_id);
+  inject_grpc_initial_metadata();
+}
+
+#[no_mangle]
+pub fn proxy_on_grpc_receive(_context_id: u32, _token: u32, _response_size: u32) {
+  set_active_context(_context_id);
+  if !receive_grpc_message(_token, _response_size as usize) {
+    if is_root_context(_context_id) {
+      get_root_context(_context_id).on_grpc_receive(_token, _response_size as usize);
+    } else {
+      get_context(_context_id).on_grpc_receive(_token, _response_size as usize);
+    }
+  }
+  run_ready_tasks();
+}
+
+#[no_mangle]
+pub fn proxy_on_grpc_close(_context_id: u32, _token: u32, _status_code: u32) {
+  set_active_context(_context_id);
+  let status = GrpcStatus::try_from(_status_code).unwrap_or(GrpcStatus::Unknown);
+  if !close_grpc_call(_token, status) {
+    if is_root_context(_context_id) {
+      get_root_context(_context_id).on_grpc_close(_token, status);
+    } else {
+      get_context(_context_id).on_grpc_close(_token, status);
+    }
+  }
+  run_ready_tasks();
+}
+
+#[no_mangle]
+pub fn proxy_on_log(_context_id: u32) {
+  set_active_context(_context_id);
+  if is_root_context(_context_id) {
+    get_root_context(_context_id).on_log();
+  } else {
+    get_context(_context_id).on_log();
+  }
+}
+
 #[no_mangle]
 pub fn proxy_on_done(_context_id: u32) -> u32 {
+  set_active_context(_context_id);
+  cancel_context_tasks(_context_id);
   0
 }
 
+#[no_mangle]
+pub fn proxy_on_delete(_context_id: u32) {
+  set_active_context(_context_id);
+  cancel_context_tasks(_context_id);
+  if is_root_context(_context_id) {
+    Logger::set_root_context_level(_context_id, None);
+  }
+  delete_context(_context_id);
+}
+
 /// Low-level Proxy-WASM APIs for the host functions.
 extern "C" {
   pub fn proxy_log(level: u32, message_data: *const u8, message_size: usize) -> u32;
+  pub fn proxy_get_log_level(level_ptr: *mut u32) -> u32;
   pub fn proxy_get_property(
     _path_ptr: *const c_char,
     _path_size: usize,
     _value_ptr_ptr: *const *mut c_char,
     _value_size_ptr: *mut usize,
   ) -> u32;
+  pub fn proxy_set_property(
+    _path_ptr: *const c_char,
+    _path_size: usize,
+    _value_ptr: *const c_char,
+    _value_size: usize,
+  ) -> u32;
+
+  pub fn proxy_set_tick_period_milliseconds(period: u32) -> u32;
+  pub fn proxy_get_current_time_nanoseconds(_result: *mut u64) -> u32;
+  pub fn proxy_set_effective_context(_context_id: u32) -> u32;
+
+  // ====================== Low-Level Proxy Shared Data API ===========================
+  pub fn proxy_get_shared_data(
+    _key_ptr: *const c_char,
+    _key_size: usize,
+    _value_ptr: *const *mut c_char,
+    _value_size: *mut usize,
+    _cas: *mut u32,
+  ) -> u32;
+  pub fn proxy_set_shared_data(
+    _key_ptr: *const c_char,
+    _key_size: usize,
+    _value_ptr: *const c_char,
+    _value_size: usize,
+    _cas: u32,
+  ) -> u32;
+  // ====================== Low-Level Proxy Shared Data API ===========================
+  // ====================== Low-Level Proxy Metrics API ===========================
+  pub fn proxy_define_metric(
+    _type: u32,
+    _name_ptr: *const c_char,
+    _name_size: usize,
+    _metric_id: *mut u32,
+  ) -> u32;
+  pub fn proxy_increment_metric(_metric_id: u32, _offset: i64) -> u32;
+  pub fn proxy_record_metric(_metric_id: u32, _value: u64) -> u32;
+  pub fn proxy_get_metric(_metric_id: u32, _value: *mut u64) -> u32;
+  // ====================== Low-Level Proxy Metrics API ===========================
+
+  // ====================== Low-Level Proxy Shared Queue API ===========================
+  pub fn proxy_register_shared_queue(
+    _queue_name_ptr: *const c_char,
+    _queue_name_size: usize,
+    _token: *mut u32,
+  ) -> u32;
+  pub fn proxy_resolve_shared_queue(
+    _vm_id_ptr: *const c_char,
+    _vm_id_size: usize,
+    _queue_name_ptr: *const c_char,
+    _queue_name_size: usize,
+    _token: *mut u32,
+  ) -> u32;
+  pub fn proxy_dequeue_shared_queue(
+    _token: u32,
+    _data_ptr: *const *mut c_char,
+    _data_size: *mut usize,
+  ) -> u32;
+  pub fn proxy_enqueue_shared_queue(
+    _token: u32,
+    _data_ptr: *const c_char,
+    _data_size: usize,
+  ) -> u32;
+  // ====================== Low-Level Proxy Shared Queue API ===========================
 
+  // ====================== Low-Level Proxy Buffer API ===========================
+  pub fn proxy_get_buffer_bytes(
+    _type: u32,
+    _start: usize,
+    _length: usize,
+    _ptr: *const *mut c_char,
+    _size_ptr: *mut usize,
+  ) -> u32;
+  pub fn proxy_set_buffer_bytes(
+    _type: u32,
+    _start: usize,
+    _length: usize,
+    _data_ptr: *const c_char,
+    _data_size: usize,
+  ) -> u32;
+  // ====================== Low-Level Proxy Buffer API ===========================
   // ====================== Low-Level Proxy Header/Header/Metadata API ===========================
   pub fn proxy_get_header_map_pairs(
     _type: u32,
@@ -169,5 +371,36 @@ extern "C" {
     _additional_response_header_pairs_size: usize,
     _grpc_status: u32,
   ) -> u32;
-// ====================== Low-Level Proxy Reply/Route/Continue API ===========================
+  pub fn proxy_clear_route_cache() -> u32;
+  pub fn proxy_continue_request() -> u32;
+  pub fn proxy_continue_response() -> u32;
+  // ====================== Low-Level Proxy Reply/Route/Continue API ===========================
+
+  // ====================== Low-Level Proxy HTTP/gRPC Callout API ===========================
+  pub fn proxy_http_call(
+    _uri_ptr: *const c_char,
+    _uri_size: usize,
+    _header_pairs_ptr: *const c_char,
+    _header_pairs_size: usize,
+    _body_ptr: *const c_char,
+    _body_size: usize,
+    _trailer_pairs_ptr: *const c_char,
+    _trailer_pairs_size: usize,
+    _timeout_milliseconds: u32,
+    _token_ptr: *mut u32,
+  ) -> u32;
+  pub fn proxy_grpc_call(
+    _service_ptr: *const c_char,
+    _service_size: usize,
+    _service_name_ptr: *const c_char,
+    _service_name_size: usize,
+    _method_name_ptr: *const c_char,
+    _method_name_size: usize,
+    _request_ptr: *const c_char,
+    _request_size: usize,
+    _timeout_milliseconds: u32,
+    _token_ptr: *mut u32,
+  ) -> u32;
+  pub fn proxy_grpc_cancel(_token: u32) -> u32;
+  // ====================== Low-Level Proxy HTTP/gRPC Callout API ===========================
 }
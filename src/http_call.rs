@@ -0,0 +1,160 @@
+use crate::buffer::*;
+use crate::executor::CallState;
+use crate::host::*;
+use crate::payload_wrapper::*;
+use crate::tracing::inject_callout_headers;
+use crate::types::*;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::os::raw::c_char;
+use std::pin::Pin;
+use std::ptr::null;
+use std::sync::Mutex;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+pub struct HttpCallResponse {
+  pub headers: HashMap<String, String>,
+  pub body: Vec<u8>,
+  pub trailers: HashMap<String, String>,
+}
+
+impl HttpCallResponse {
+  pub fn status(&self) -> Option<u32> {
+    self.headers.get(":status").and_then(|s| s.parse().ok())
+  }
+}
+
+lazy_static! {
+  static ref HTTP_CALLS: Mutex<HashMap<u32, CallState<Result<HttpCallResponse, String>>>> =
+    Mutex::new(HashMap::new());
+}
+
+fn pairs_ptr(buffer: &[u8]) -> *const c_char {
+  if buffer.is_empty() {
+    null::<c_char>()
+  } else {
+    buffer.as_ptr() as *const c_char
+  }
+}
+
+/// Sends an HTTP request to `cluster` and returns the token identifying the call. `headers`
+/// must contain `:method`, `:path` and `:authority`. The response is delivered to
+/// `on_http_call_response` of the calling context. Calls from stream contexts carry the trace
+/// context of the request, see `tracing::set_callout_propagation`.
+pub fn dispatch_http_call(
+  cluster: &str,
+  headers: &[(String, String)],
+  body: Option<&[u8]>,
+  trailers: &[(String, String)],
+  timeout: Duration,
+) -> Result<u32, String> {
+  let mut headers = headers.to_vec();
+  inject_callout_headers(&mut headers);
+  let header_buffer = pairs_into_bytes(&headers);
+  let trailer_buffer = pairs_into_bytes(trailers);
+  let body = body.unwrap_or(&[]);
+  let mut token: u32 = 0;
+  unsafe {
+    let code = proxy_http_call(
+      cluster.as_ptr() as *const c_char,
+      cluster.len(),
+      pairs_ptr(&header_buffer),
+      header_buffer.len(),
+      body.as_ptr() as *const c_char,
+      body.len(),
+      pairs_ptr(&trailer_buffer),
+      trailer_buffer.len(),
+      timeout.as_millis() as u32,
+      &mut token,
+    );
+    match WasmResult::try_from(code) {
+      Ok(WasmResult::Ok) => Ok(token),
+      Ok(r) => Err(r.to_string()),
+      Err(e) => Err(e),
+    }
+  }
+}
+
+/// Same as `dispatch_http_call`, but the response is awaited instead of being delivered to
+/// `on_http_call_response`.
+pub fn http_call(
+  cluster: &str,
+  headers: &[(String, String)],
+  body: Option<&[u8]>,
+  trailers: &[(String, String)],
+  timeout: Duration,
+) -> HttpCallFuture {
+  let token = dispatch_http_call(cluster, headers, body, trailers, timeout);
+  if let Ok(token) = token {
+    HTTP_CALLS
+      .lock()
+      .unwrap()
+      .insert(token, CallState::Pending(None));
+  }
+  HttpCallFuture { token }
+}
+
+pub struct HttpCallFuture {
+  token: Result<u32, String>,
+}
+
+impl Future for HttpCallFuture {
+  type Output = Result<HttpCallResponse, String>;
+  fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+    let token = match &self.token {
+      Ok(token) => *token,
+      Err(e) => return Poll::Ready(Err(e.clone())),
+    };
+    let mut calls = HTTP_CALLS.lock().unwrap();
+    match calls.remove(&token) {
+      Some(CallState::Complete(response)) => Poll::Ready(response),
+      Some(CallState::Pending(_)) => {
+        calls.insert(token, CallState::Pending(Some(cx.waker().clone())));
+        Poll::Pending
+      }
+      None => Poll::Ready(Err("http call response was already taken".to_string())),
+    }
+  }
+}
+
+impl Drop for HttpCallFuture {
+  fn drop(&mut self) {
+    if let Ok(token) = self.token {
+      HTTP_CALLS.lock().unwrap().remove(&token);
+    }
+  }
+}
+
+/// Hands the response over to the future awaiting `token`. Returns false if the call is not
+/// awaited, in which case the response is left to `on_http_call_response`.
+pub(crate) fn complete_http_call(token: u32, num_headers: u32, body_size: usize) -> bool {
+  if !HTTP_CALLS.lock().unwrap().contains_key(&token) {
+    return false;
+  }
+  // A call which failed or timed out has no headers.
+  let response = if num_headers == 0 {
+    Err("http call failed".to_string())
+  } else {
+    let body = if body_size == 0 {
+      Ok(Vec::new())
+    } else {
+      get_buffer_bytes(BufferType::HttpCallResponseBody, 0, body_size)
+    };
+    body.map(|body| HttpCallResponse {
+      headers: get_header_map_pairs(HeaderMapType::HttpCallResponseHeaders).unwrap_or_default(),
+      body,
+      trailers: get_header_map_pairs(HeaderMapType::HttpCallResponseTrailers).unwrap_or_default(),
+    })
+  };
+  let previous = HTTP_CALLS
+    .lock()
+    .unwrap()
+    .insert(token, CallState::Complete(response));
+  if let Some(CallState::Pending(Some(waker))) = previous {
+    waker.wake();
+  }
+  true
+}
@@ -0,0 +1,371 @@
+use crate::body::{BodyHandler, BodyPolicy};
+use crate::types::*;
+use log::warn;
+use serde_json::Value;
+
+// ====================== JSON Pointer API (RFC 6901) =============================
+fn unescape_token(token: &str) -> String {
+  token.replace("~1", "/").replace("~0", "~")
+}
+
+// Splits a non-root pointer into the pointer of its parent and its unescaped last token.
+fn split_pointer(pointer: &str) -> Result<(&str, String), String> {
+  if !pointer.starts_with('/') {
+    return Err(format!("invalid json pointer: {:?}", pointer));
+  }
+  let index = pointer.rfind('/').unwrap_or(0);
+  Ok((&pointer[..index], unescape_token(&pointer[index + 1..])))
+}
+
+fn array_index(token: &str) -> Result<usize, String> {
+  let valid = !token.is_empty()
+    && token.bytes().all(|b| b.is_ascii_digit())
+    && (token == "0" || !token.starts_with('0'));
+  match valid {
+    true => token
+      .parse()
+      .map_err(|_| format!("invalid array index: {}", token)),
+    false => Err(format!("invalid array index: {}", token)),
+  }
+}
+
+pub fn pointer_get<'a>(doc: &'a Value, pointer: &str) -> Option<&'a Value> {
+  doc.pointer(pointer)
+}
+
+/// Inserts `value` at `pointer`. Object members are overwritten, array elements are shifted and
+/// `-` appends to an array.
+pub fn pointer_add(doc: &mut Value, pointer: &str, value: Value) -> Result<(), String> {
+  if pointer.is_empty() {
+    *doc = value;
+    return Ok(());
+  }
+  let (parent, token) = split_pointer(pointer)?;
+  match doc.pointer_mut(parent) {
+    Some(Value::Object(members)) => {
+      members.insert(token, value);
+      Ok(())
+    }
+    Some(Value::Array(items)) => {
+      let index = match token.as_str() {
+        "-" => items.len(),
+        _ => array_index(&token)?,
+      };
+      if index > items.len() {
+        return Err(format!("array index out of bounds: {}", pointer));
+      }
+      items.insert(index, value);
+      Ok(())
+    }
+    Some(_) => Err(format!("parent is not a container: {}", pointer)),
+    None => Err(format!("path not found: {}", pointer)),
+  }
+}
+
+/// Removes and returns the value at `pointer`.
+pub fn pointer_remove(doc: &mut Value, pointer: &str) -> Result<Value, String> {
+  if pointer.is_empty() {
+    return Err("cannot remove the root".to_string());
+  }
+  let (parent, token) = split_pointer(pointer)?;
+  let removed = match doc.pointer_mut(parent) {
+    Some(Value::Object(members)) => members.remove(&token),
+    Some(Value::Array(items)) => {
+      let index = array_index(&token)?;
+      match index < items.len() {
+        true => Some(items.remove(index)),
+        false => None,
+      }
+    }
+    _ => None,
+  };
+  removed.ok_or_else(|| format!("path not found: {}", pointer))
+}
+
+/// Replaces the existing value at `pointer` and returns it.
+pub fn pointer_replace(doc: &mut Value, pointer: &str, value: Value) -> Result<Value, String> {
+  match doc.pointer_mut(pointer) {
+    Some(target) => Ok(std::mem::replace(target, value)),
+    None => Err(format!("path not found: {}", pointer)),
+  }
+}
+// ====================== JSON Pointer API (RFC 6901) =============================
+
+// ====================== JSON Patch API (RFC 6902) =============================
+#[derive(Clone, Debug, PartialEq)]
+pub enum PatchOperation {
+  Add { path: String, value: Value },
+  Remove { path: String },
+  Replace { path: String, value: Value },
+  Move { from: String, path: String },
+  Copy { from: String, path: String },
+  Test { path: String, value: Value },
+}
+
+impl PatchOperation {
+  /// Parses a JSON Patch document, which is an array of operation objects.
+  pub fn parse_patch(patch: &Value) -> Result<Vec<PatchOperation>, String> {
+    match patch {
+      Value::Array(operations) => operations.iter().map(PatchOperation::parse).collect(),
+      _ => Err("json patch must be an array".to_string()),
+    }
+  }
+
+  pub fn parse(operation: &Value) -> Result<PatchOperation, String> {
+    let string = |key: &str| match operation.get(key) {
+      Some(Value::String(s)) => Ok(s.clone()),
+      _ => Err(format!(
+        "json patch operation lacks {:?}: {}",
+        key, operation
+      )),
+    };
+    let value = || match operation.get("value") {
+      Some(v) => Ok(v.clone()),
+      None => Err(format!(
+        "json patch operation lacks \"value\": {}",
+        operation
+      )),
+    };
+    let path = string("path")?;
+    match string("op")?.as_str() {
+      "add" => Ok(PatchOperation::Add {
+        path,
+        value: value()?,
+      }),
+      "remove" => Ok(PatchOperation::Remove { path }),
+      "replace" => Ok(PatchOperation::Replace {
+        path,
+        value: value()?,
+      }),
+      "move" => Ok(PatchOperation::Move {
+        from: string("from")?,
+        path,
+      }),
+      "copy" => Ok(PatchOperation::Copy {
+        from: string("from")?,
+        path,
+      }),
+      "test" => Ok(PatchOperation::Test {
+        path,
+        value: value()?,
+      }),
+      op => Err(format!("unknown json patch operation: {}", op)),
+    }
+  }
+
+  fn apply(&self, doc: &mut Value) -> Result<(), String> {
+    match self {
+      PatchOperation::Add { path, value } => pointer_add(doc, path, value.clone()),
+      PatchOperation::Remove { path } => pointer_remove(doc, path).map(|_| ()),
+      PatchOperation::Replace { path, value } => {
+        pointer_replace(doc, path, value.clone()).map(|_| ())
+      }
+      PatchOperation::Move { from, path } => {
+        if path.starts_with(&format!("{}/", from)) {
+          return Err(format!("cannot move {} into itself", from));
+        }
+        let value = pointer_remove(doc, from)?;
+        pointer_add(doc, path, value)
+      }
+      PatchOperation::Copy { from, path } => match doc.pointer(from).cloned() {
+        Some(value) => pointer_add(doc, path, value),
+        None => Err(format!("path not found: {}", from)),
+      },
+      PatchOperation::Test { path, value } => match doc.pointer(path) {
+        Some(actual) if actual == value => Ok(()),
+        _ => Err(format!("test failed: {}", path)),
+      },
+    }
+  }
+}
+
+/// Applies `operations` in order. The document is left untouched if any of them fails.
+pub fn apply_patch(doc: &mut Value, operations: &[PatchOperation]) -> Result<(), String> {
+  let mut patched = doc.clone();
+  for operation in operations {
+    operation.apply(&mut patched)?;
+  }
+  *doc = patched;
+  Ok(())
+}
+// ====================== JSON Patch API (RFC 6902) =============================
+
+/// Buffers a JSON body, up to `limit` bytes, and rewrites it. Bodies which are not JSON are
+/// passed through untouched. A rewritten body may change size, so `on_headers` must be called
+/// from the headers callback of the direction to drop `content-length` before the headers are
+/// forwarded.
+pub struct JsonBody {
+  body: BodyHandler,
+}
+
+impl JsonBody {
+  pub fn request(limit: usize) -> JsonBody {
+    JsonBody {
+      body: BodyHandler::request(BodyPolicy::Buffer(limit)),
+    }
+  }
+
+  pub fn response(limit: usize) -> JsonBody {
+    JsonBody {
+      body: BodyHandler::response(BodyPolicy::Buffer(limit)),
+    }
+  }
+
+  pub fn handler(&self) -> &BodyHandler {
+    &self.body
+  }
+
+  /// Handles a headers callback. Removes `content-length`, which no longer holds once the body
+  /// is rewritten.
+  pub fn on_headers(&self) {
+    match self.body.remove_content_length() {
+      WasmResult::Ok | WasmResult::NotFound => {}
+      r => warn!("failed to remove content-length: {}", r),
+    }
+  }
+
+  /// Handles a body callback. Once the whole body is buffered, `f` is called with the parsed
+  /// document and returns whether it modified it.
+  pub fn on_body<F>(&self, body_buffer_length: usize, end_of_stream: bool, f: F) -> FilterDataStatus
+  where
+    F: FnOnce(&mut Value) -> bool,
+  {
+    self
+      .body
+      .on_body(body_buffer_length, end_of_stream, |data, _| {
+        self.rewrite(data, f)
+      })
+  }
+
+  /// Handles a trailers callback, for streams whose body does not end with `end_of_stream`. See
+  /// `BodyHandler::on_trailers`.
+  pub fn on_trailers<F>(&self, f: F) -> FilterTrailersStatus
+  where
+    F: FnOnce(&mut Value) -> bool,
+  {
+    self.body.on_trailers(|data, _| self.rewrite(data, f))
+  }
+
+  fn rewrite<F>(&self, data: &[u8], f: F) -> Option<Vec<u8>>
+  where
+    F: FnOnce(&mut Value) -> bool,
+  {
+    if data.is_empty() {
+      return None;
+    }
+    let mut doc: Value = match serde_json::from_slice(data) {
+      Ok(doc) => doc,
+      Err(e) => {
+        warn!("{:?} body is not json: {}", self.body.direction(), e);
+        return None;
+      }
+    };
+    if !f(&mut doc) {
+      return None;
+    }
+    match serde_json::to_vec(&doc) {
+      Ok(rewritten) => Some(rewritten),
+      Err(e) => {
+        warn!("failed to serialize json: {}", e);
+        None
+      }
+    }
+  }
+
+  /// Same as `on_body`, applying a JSON Patch to the document.
+  pub fn patch(
+    &self,
+    body_buffer_length: usize,
+    end_of_stream: bool,
+    operations: &[PatchOperation],
+  ) -> FilterDataStatus {
+    self.on_body(body_buffer_length, end_of_stream, |doc| {
+      match apply_patch(doc, operations) {
+        Ok(()) => true,
+        Err(e) => {
+          warn!("failed to apply json patch: {}", e);
+          false
+        }
+      }
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn patch(doc: Value, patch: Value) -> Result<Value, String> {
+    let mut doc = doc;
+    apply_patch(&mut doc, &PatchOperation::parse_patch(&patch)?)?;
+    Ok(doc)
+  }
+
+  #[test]
+  fn pointer_escapes_and_array_indices() {
+    let mut doc = json!({"a/b": {"m~n": [1, 2]}});
+    assert_eq!(pointer_get(&doc, "/a~1b/m~0n/1"), Some(&json!(2)));
+    pointer_add(&mut doc, "/a~1b/m~0n/-", json!(3)).unwrap();
+    pointer_add(&mut doc, "/a~1b/m~0n/0", json!(0)).unwrap();
+    assert_eq!(doc, json!({"a/b": {"m~n": [0, 1, 2, 3]}}));
+    assert!(pointer_add(&mut doc, "/a~1b/m~0n/5", json!(9)).is_err());
+    assert!(pointer_add(&mut doc, "/a~1b/m~0n/01", json!(9)).is_err());
+    assert_eq!(pointer_remove(&mut doc, "/a~1b/m~0n/0"), Ok(json!(0)));
+    assert_eq!(
+      pointer_replace(&mut doc, "/a~1b", json!(null)),
+      Ok(json!({"m~n": [1, 2, 3]}))
+    );
+    assert!(pointer_replace(&mut doc, "/missing", json!(1)).is_err());
+  }
+
+  #[test]
+  fn applies_rfc6902_operations() {
+    assert_eq!(
+      patch(
+        json!({"foo": "bar"}),
+        json!([{"op": "add", "path": "/baz", "value": "qux"}])
+      ),
+      Ok(json!({"foo": "bar", "baz": "qux"}))
+    );
+    assert_eq!(
+      patch(
+        json!({"foo": {"bar": "baz", "waldo": "fred"}, "qux": {"corge": "grault"}}),
+        json!([{"op": "move", "from": "/foo/waldo", "path": "/qux/thud"}])
+      ),
+      Ok(json!({"foo": {"bar": "baz"}, "qux": {"corge": "grault", "thud": "fred"}}))
+    );
+    assert_eq!(
+      patch(
+        json!({"foo": ["bar", "baz"]}),
+        json!([{"op": "copy", "from": "/foo/0", "path": "/foo/-"}])
+      ),
+      Ok(json!({"foo": ["bar", "baz", "bar"]}))
+    );
+    assert!(patch(
+      json!({"foo": {}}),
+      json!([{"op": "move", "from": "/foo", "path": "/foo/bar"}])
+    )
+    .is_err());
+  }
+
+  #[test]
+  fn failed_patch_leaves_document_untouched() {
+    let mut doc = json!({"baz": "qux", "foo": "bar"});
+    let operations = PatchOperation::parse_patch(&json!([
+      {"op": "replace", "path": "/baz", "value": "boo"},
+      {"op": "test", "path": "/foo", "value": "other"},
+    ]))
+    .unwrap();
+    assert!(apply_patch(&mut doc, &operations).is_err());
+    assert_eq!(doc, json!({"baz": "qux", "foo": "bar"}));
+  }
+
+  #[test]
+  fn rejects_malformed_operations() {
+    assert!(PatchOperation::parse_patch(&json!({"op": "add"})).is_err());
+    assert!(PatchOperation::parse(&json!({"op": "add", "path": "/a"})).is_err());
+    assert!(PatchOperation::parse(&json!({"op": "move", "path": "/a"})).is_err());
+    assert!(PatchOperation::parse(&json!({"op": "frob", "path": "/a"})).is_err());
+  }
+}
@@ -1,9 +1,34 @@
+pub mod access_log;
+pub mod body;
+pub mod cache;
+#[cfg(any(feature = "gzip", feature = "brotli"))]
+pub mod compression;
 pub mod context;
 pub mod envoy_log;
+pub mod executor;
+pub mod extensions;
+pub mod filter_chain;
+pub mod grpc_call;
+pub mod grpc_frame;
+pub mod http_call;
+#[cfg(feature = "json")]
+pub mod json_body;
+#[cfg(feature = "jwt")]
+pub mod jwt;
+pub mod metadata;
+pub mod metrics;
 pub mod payload;
+pub mod queue;
+pub mod rate_limit;
 pub mod reply;
+pub mod request_head;
+pub mod service;
+pub mod shared_data;
+pub mod time;
+pub mod tracing;
 pub mod types;
 
 mod buffer;
+mod encoding;
 mod host;
 mod payload_wrapper;
@@ -0,0 +1,325 @@
+use crate::encoding::{decode_varint, encode_varint};
+use crate::payload::{get_property, set_property};
+use crate::types::*;
+use std::collections::BTreeMap;
+
+/// Value of dynamic metadata, mirroring `google.protobuf.Value`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetadataValue {
+  Null,
+  Number(f64),
+  String(String),
+  Bool(bool),
+  Struct(BTreeMap<String, MetadataValue>),
+  List(Vec<MetadataValue>),
+}
+
+impl MetadataValue {
+  pub fn as_str(&self) -> Option<&str> {
+    match self {
+      MetadataValue::String(s) => Some(s),
+      _ => None,
+    }
+  }
+
+  pub fn as_f64(&self) -> Option<f64> {
+    match self {
+      MetadataValue::Number(n) => Some(*n),
+      _ => None,
+    }
+  }
+
+  pub fn as_bool(&self) -> Option<bool> {
+    match self {
+      MetadataValue::Bool(b) => Some(*b),
+      _ => None,
+    }
+  }
+
+  pub fn as_struct(&self) -> Option<&BTreeMap<String, MetadataValue>> {
+    match self {
+      MetadataValue::Struct(fields) => Some(fields),
+      _ => None,
+    }
+  }
+
+  pub fn as_list(&self) -> Option<&[MetadataValue]> {
+    match self {
+      MetadataValue::List(values) => Some(values),
+      _ => None,
+    }
+  }
+}
+
+impl From<&str> for MetadataValue {
+  fn from(s: &str) -> MetadataValue {
+    MetadataValue::String(s.to_string())
+  }
+}
+
+impl From<String> for MetadataValue {
+  fn from(s: String) -> MetadataValue {
+    MetadataValue::String(s)
+  }
+}
+
+impl From<f64> for MetadataValue {
+  fn from(n: f64) -> MetadataValue {
+    MetadataValue::Number(n)
+  }
+}
+
+impl From<bool> for MetadataValue {
+  fn from(b: bool) -> MetadataValue {
+    MetadataValue::Bool(b)
+  }
+}
+
+#[cfg(feature = "json")]
+impl From<serde_json::Value> for MetadataValue {
+  fn from(value: serde_json::Value) -> MetadataValue {
+    use serde_json::Value;
+    match value {
+      Value::Null => MetadataValue::Null,
+      Value::Bool(b) => MetadataValue::Bool(b),
+      Value::Number(n) => MetadataValue::Number(n.as_f64().unwrap_or(0.0)),
+      Value::String(s) => MetadataValue::String(s),
+      Value::Array(values) => MetadataValue::List(values.into_iter().map(Into::into).collect()),
+      Value::Object(fields) => {
+        MetadataValue::Struct(fields.into_iter().map(|(k, v)| (k, v.into())).collect())
+      }
+    }
+  }
+}
+
+// ====================== google.protobuf.Struct Encoding =============================
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LENGTH_DELIMITED: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+fn encode_tag(field: u64, wire_type: u64, buffer: &mut Vec<u8>) {
+  encode_varint(field << 3 | wire_type, buffer);
+}
+
+fn encode_bytes(field: u64, bytes: &[u8], buffer: &mut Vec<u8>) {
+  encode_tag(field, WIRE_LENGTH_DELIMITED, buffer);
+  encode_varint(bytes.len() as u64, buffer);
+  buffer.extend_from_slice(bytes);
+}
+
+fn encode_value(value: &MetadataValue) -> Vec<u8> {
+  let mut buffer = Vec::new();
+  match value {
+    MetadataValue::Null => {
+      encode_tag(1, WIRE_VARINT, &mut buffer);
+      encode_varint(0, &mut buffer);
+    }
+    MetadataValue::Number(n) => {
+      encode_tag(2, WIRE_FIXED64, &mut buffer);
+      buffer.extend_from_slice(&n.to_le_bytes());
+    }
+    MetadataValue::String(s) => encode_bytes(3, s.as_bytes(), &mut buffer),
+    MetadataValue::Bool(b) => {
+      encode_tag(4, WIRE_VARINT, &mut buffer);
+      encode_varint(*b as u64, &mut buffer);
+    }
+    MetadataValue::Struct(fields) => encode_bytes(5, &encode_struct(fields), &mut buffer),
+    MetadataValue::List(values) => {
+      let mut list = Vec::new();
+      for value in values {
+        encode_bytes(1, &encode_value(value), &mut list);
+      }
+      encode_bytes(6, &list, &mut buffer);
+    }
+  }
+  buffer
+}
+
+/// Serializes `fields` as a `google.protobuf.Struct`.
+pub fn encode_struct(fields: &BTreeMap<String, MetadataValue>) -> Vec<u8> {
+  let mut buffer = Vec::new();
+  for (key, value) in fields {
+    let mut entry = Vec::new();
+    encode_bytes(1, key.as_bytes(), &mut entry);
+    encode_bytes(2, &encode_value(value), &mut entry);
+    encode_bytes(1, &entry, &mut buffer);
+  }
+  buffer
+}
+
+// Field of a message being decoded, with length-delimited payloads borrowed from the buffer.
+enum Field<'a> {
+  Varint(u64),
+  Fixed64(u64),
+  Bytes(&'a [u8]),
+}
+
+fn decode_fields(buffer: &[u8]) -> Result<Vec<(u64, Field<'_>)>, String> {
+  let malformed = || "malformed protobuf message".to_string();
+  let mut fields = Vec::new();
+  let mut offset = 0;
+  while offset < buffer.len() {
+    let tag = decode_varint(buffer, &mut offset).ok_or_else(malformed)?;
+    let field = match tag & 7 {
+      WIRE_VARINT => Field::Varint(decode_varint(buffer, &mut offset).ok_or_else(malformed)?),
+      WIRE_FIXED64 => {
+        let bytes = buffer.get(offset..offset + 8).ok_or_else(malformed)?;
+        offset += 8;
+        let mut fixed = [0u8; 8];
+        fixed.copy_from_slice(bytes);
+        Field::Fixed64(u64::from_le_bytes(fixed))
+      }
+      WIRE_LENGTH_DELIMITED => {
+        let length = decode_varint(buffer, &mut offset).ok_or_else(malformed)? as usize;
+        let end = offset.checked_add(length).ok_or_else(malformed)?;
+        let bytes = buffer.get(offset..end).ok_or_else(malformed)?;
+        offset = end;
+        Field::Bytes(bytes)
+      }
+      WIRE_FIXED32 => {
+        offset += 4;
+        if offset > buffer.len() {
+          return Err(malformed());
+        }
+        continue;
+      }
+      _ => return Err(malformed()),
+    };
+    fields.push((tag >> 3, field));
+  }
+  Ok(fields)
+}
+
+fn decode_value(buffer: &[u8]) -> Result<MetadataValue, String> {
+  let mut value = MetadataValue::Null;
+  for (number, field) in decode_fields(buffer)? {
+    value = match (number, field) {
+      (1, Field::Varint(_)) => MetadataValue::Null,
+      (2, Field::Fixed64(bits)) => MetadataValue::Number(f64::from_bits(bits)),
+      (3, Field::Bytes(bytes)) => MetadataValue::String(String::from_utf8_lossy(bytes).to_string()),
+      (4, Field::Varint(b)) => MetadataValue::Bool(b != 0),
+      (5, Field::Bytes(bytes)) => MetadataValue::Struct(decode_struct(bytes)?),
+      (6, Field::Bytes(bytes)) => {
+        let mut values = Vec::new();
+        for (number, field) in decode_fields(bytes)? {
+          if let (1, Field::Bytes(bytes)) = (number, field) {
+            values.push(decode_value(bytes)?);
+          }
+        }
+        MetadataValue::List(values)
+      }
+      _ => continue,
+    };
+  }
+  Ok(value)
+}
+
+/// Parses a serialized `google.protobuf.Struct`.
+pub fn decode_struct(buffer: &[u8]) -> Result<BTreeMap<String, MetadataValue>, String> {
+  let mut fields = BTreeMap::new();
+  for (number, field) in decode_fields(buffer)? {
+    let entry = match (number, field) {
+      (1, Field::Bytes(entry)) => entry,
+      _ => continue,
+    };
+    let mut key = String::new();
+    let mut value = MetadataValue::Null;
+    for (number, field) in decode_fields(entry)? {
+      match (number, field) {
+        (1, Field::Bytes(bytes)) => key = String::from_utf8_lossy(bytes).to_string(),
+        (2, Field::Bytes(bytes)) => value = decode_value(bytes)?,
+        _ => {}
+      }
+    }
+    fields.insert(key, value);
+  }
+  Ok(fields)
+}
+// ====================== google.protobuf.Struct Encoding =============================
+
+// ====================== Dynamic Metadata API =============================
+/// Returns the dynamic metadata `namespace`, as set by other filters of the stream.
+pub fn get_dynamic_metadata_struct(
+  namespace: &str,
+) -> Result<Option<BTreeMap<String, MetadataValue>>, String> {
+  match get_property(&["metadata", "filter_metadata", namespace])? {
+    Some(buffer) => decode_struct(&buffer).map(Some),
+    None => Ok(None),
+  }
+}
+
+pub fn get_dynamic_metadata(namespace: &str, key: &str) -> Result<Option<MetadataValue>, String> {
+  Ok(get_dynamic_metadata_struct(namespace)?.and_then(|mut fields| fields.remove(key)))
+}
+// ====================== Dynamic Metadata API =============================
+
+// ====================== Filter State API =============================
+/// Stores `value` as a filter state object of the stream. Envoy prefixes the names of objects
+/// set by Wasm filters, so other filters see it as `wasm.<key>`.
+pub fn set_filter_state(key: &str, value: &[u8]) -> WasmResult {
+  set_property(&[key], value)
+}
+
+/// Reads the filter state object stored by `set_filter_state` under `key`, in this or another
+/// Wasm filter. Objects of other filters can be read with `get_property(&["filter_state", name])`.
+pub fn get_filter_state(key: &str) -> Result<Option<Vec<u8>>, String> {
+  get_property(&["filter_state", &format!("wasm.{}", key)])
+}
+
+/// Stores `fields` as a serialized `google.protobuf.Struct` in the filter state object `key`.
+/// The ABI has no setter for dynamic metadata, so this is the way to hand structured values to
+/// other Wasm filters of the stream. Each call replaces the fields stored by the previous one.
+pub fn set_filter_state_struct(key: &str, fields: &BTreeMap<String, MetadataValue>) -> WasmResult {
+  set_filter_state(key, &encode_struct(fields))
+}
+
+/// Stores a struct with the single field `field` in the filter state object `key`, see
+/// `set_filter_state_struct`.
+pub fn set_filter_state_value(key: &str, field: &str, value: MetadataValue) -> WasmResult {
+  let mut fields = BTreeMap::new();
+  fields.insert(field.to_string(), value);
+  set_filter_state_struct(key, &fields)
+}
+
+/// Reads a struct stored by `set_filter_state_struct`.
+pub fn get_filter_state_struct(
+  key: &str,
+) -> Result<Option<BTreeMap<String, MetadataValue>>, String> {
+  match get_filter_state(key)? {
+    Some(buffer) => decode_struct(&buffer).map(Some),
+    None => Ok(None),
+  }
+}
+// ====================== Filter State API =============================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn structs_round_trip() {
+    let mut nested = BTreeMap::new();
+    nested.insert("enabled".to_string(), MetadataValue::Bool(true));
+    nested.insert("none".to_string(), MetadataValue::Null);
+    let mut fields = BTreeMap::new();
+    fields.insert("name".to_string(), MetadataValue::from("alice"));
+    fields.insert("score".to_string(), MetadataValue::Number(-1.5));
+    fields.insert("nested".to_string(), MetadataValue::Struct(nested));
+    fields.insert(
+      "list".to_string(),
+      MetadataValue::List(vec![MetadataValue::from(1.0), MetadataValue::from("")]),
+    );
+    assert_eq!(decode_struct(&encode_struct(&fields)), Ok(fields));
+  }
+
+  #[test]
+  fn decodes_protobuf_struct() {
+    // {"a": "b"}, as serialized by protobuf.
+    let buffer = [0x0a, 8, 0x0a, 1, b'a', 0x12, 3, 0x1a, 1, b'b'];
+    let fields = decode_struct(&buffer).unwrap();
+    assert_eq!(fields.get("a").and_then(|v| v.as_str()), Some("b"));
+    assert_eq!(encode_struct(&fields), buffer.to_vec());
+    assert!(decode_struct(&buffer[..buffer.len() - 1]).is_err());
+  }
+}
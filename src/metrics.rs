@@ -0,0 +1,99 @@
+use crate::host::*;
+use crate::time::Instant;
+use crate::types::*;
+use std::os::raw::c_char;
+use std::time::Duration;
+
+fn define_metric(mtype: MetricType, name: &str) -> Result<u32, String> {
+  let mut id: u32 = 0;
+  unsafe {
+    match to_wasm_result(proxy_define_metric(
+      metric_type_to_int(mtype),
+      name.as_ptr() as *const c_char,
+      name.len(),
+      &mut id,
+    )) {
+      WasmResult::Ok => Ok(id),
+      r => Err(r.to_string()),
+    }
+  }
+}
+
+fn get_metric(id: u32) -> Result<u64, String> {
+  let mut value: u64 = 0;
+  unsafe {
+    match to_wasm_result(proxy_get_metric(id, &mut value)) {
+      WasmResult::Ok => Ok(value),
+      r => Err(r.to_string()),
+    }
+  }
+}
+
+/// Metrics are defined per VM. Defining the same name again returns the same metric, so they are
+/// usually defined once from the root context and kept around.
+#[derive(Clone, Copy, Debug)]
+pub struct Counter {
+  id: u32,
+}
+
+impl Counter {
+  pub fn define(name: &str) -> Result<Counter, String> {
+    define_metric(MetricType::Counter, name).map(|id| Counter { id })
+  }
+
+  pub fn increment(&self, offset: u64) -> WasmResult {
+    unsafe { to_wasm_result(proxy_increment_metric(self.id, offset as i64)) }
+  }
+
+  pub fn value(&self) -> Result<u64, String> {
+    get_metric(self.id)
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Gauge {
+  id: u32,
+}
+
+impl Gauge {
+  pub fn define(name: &str) -> Result<Gauge, String> {
+    define_metric(MetricType::Gauge, name).map(|id| Gauge { id })
+  }
+
+  pub fn set(&self, value: u64) -> WasmResult {
+    unsafe { to_wasm_result(proxy_record_metric(self.id, value)) }
+  }
+
+  pub fn add(&self, offset: i64) -> WasmResult {
+    unsafe { to_wasm_result(proxy_increment_metric(self.id, offset)) }
+  }
+
+  pub fn value(&self) -> Result<u64, String> {
+    get_metric(self.id)
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Histogram {
+  id: u32,
+}
+
+impl Histogram {
+  pub fn define(name: &str) -> Result<Histogram, String> {
+    define_metric(MetricType::Histogram, name).map(|id| Histogram { id })
+  }
+
+  pub fn record(&self, value: u64) -> WasmResult {
+    unsafe { to_wasm_result(proxy_record_metric(self.id, value)) }
+  }
+
+  /// Records `duration` in milliseconds, the unit of Envoy's timing histograms.
+  pub fn record_duration(&self, duration: Duration) -> WasmResult {
+    self.record(duration.as_millis() as u64)
+  }
+
+  /// Records the time elapsed since `start`, e.g. taken in `on_request_headers`.
+  pub fn record_since(&self, start: Instant) -> WasmResult {
+    self.record_duration(start.elapsed())
+  }
+}
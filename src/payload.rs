@@ -1,6 +1,12 @@
+use crate::payload_wrapper;
 use crate::payload_wrapper::*;
 use crate::types::*;
+#[cfg(feature = "http-types")]
+use log::warn;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ROUTE_CACHE_AUTO_CLEAR: AtomicBool = AtomicBool::new(false);
 
 // ====================== Request Header Processing API ===========================
 pub fn get_request_header_pairs() -> Result<HashMap<String, String>, String> {
@@ -8,7 +14,12 @@ pub fn get_request_header_pairs() -> Result<HashMap<String, String>, String> {
 }
 
 pub fn set_request_header_pairs(_pairs: &HashMap<String, String>) -> WasmResult {
-  set_header_map_pairs(HeaderMapType::RequestHeaders, _pairs)
+  let result = set_header_map_pairs(HeaderMapType::RequestHeaders, _pairs);
+  if _pairs.keys().any(|k| is_pseudo_header(k)) {
+    auto_clear_route_cache(result)
+  } else {
+    result
+  }
 }
 
 pub fn get_request_header(key: String) -> Result<Box<WasmData>, String> {
@@ -16,20 +27,60 @@ pub fn get_request_header(key: String) -> Result<Box<WasmData>, String> {
 }
 
 pub fn add_request_header(key: String, value: String) -> WasmResult {
-  add_header_map_value(HeaderMapType::RequestHeaders, key, value)
+  let pseudo = is_pseudo_header(&key);
+  let result = add_header_map_value(HeaderMapType::RequestHeaders, key, value);
+  if pseudo {
+    auto_clear_route_cache(result)
+  } else {
+    result
+  }
 }
 
 pub fn replace_request_header(key: String, value: String) -> WasmResult {
-  replace_header_map_value(HeaderMapType::RequestHeaders, key, value)
+  let pseudo = is_pseudo_header(&key);
+  let result = replace_header_map_value(HeaderMapType::RequestHeaders, key, value);
+  if pseudo {
+    auto_clear_route_cache(result)
+  } else {
+    result
+  }
 }
 
 pub fn remove_request_header(key: String) -> WasmResult {
-  remove_header_map_value(HeaderMapType::RequestHeaders, key)
+  let pseudo = is_pseudo_header(&key);
+  let result = remove_header_map_value(HeaderMapType::RequestHeaders, key);
+  if pseudo {
+    auto_clear_route_cache(result)
+  } else {
+    result
+  }
 }
 
 pub fn get_request_header_size() -> u32 {
   get_header_map_value_size(HeaderMapType::RequestHeaders)
 }
+
+/// Makes Envoy pick the route again for the current request. Required after modifying
+/// headers which take part in route matching, such as `:path` or `:authority`.
+pub fn clear_route_cache() -> WasmResult {
+  payload_wrapper::clear_route_cache()
+}
+
+/// When enabled, request header mutations touching pseudo-headers clear the route cache.
+pub fn set_route_cache_auto_clear(enabled: bool) {
+  ROUTE_CACHE_AUTO_CLEAR.store(enabled, Ordering::Relaxed);
+}
+
+fn is_pseudo_header(key: &str) -> bool {
+  key.starts_with(':')
+}
+
+fn auto_clear_route_cache(result: WasmResult) -> WasmResult {
+  match result {
+    WasmResult::Ok if ROUTE_CACHE_AUTO_CLEAR.load(Ordering::Relaxed) => clear_route_cache(),
+    r => r,
+  }
+}
 // ====================== Request Header Processing API ===========================
 
 // ====================== Response Header Processing API ===========================
@@ -121,3 +172,211 @@ pub fn get_response_trailer_size() -> u32 {
   get_header_map_value_size(HeaderMapType::ResponseTrailers)
 }
 // ====================== Response Trailer Processing API ===========================
+
+// ====================== Buffer Processing API ===========================
+pub fn get_buffer_bytes(btype: BufferType, start: usize, length: usize) -> Result<Vec<u8>, String> {
+  payload_wrapper::get_buffer_bytes(btype, start, length)
+}
+
+/// Replaces `length` bytes from `start` with `data`.
+pub fn set_buffer_bytes(btype: BufferType, start: usize, length: usize, data: &[u8]) -> WasmResult {
+  payload_wrapper::set_buffer_bytes(btype, start, length, data)
+}
+
+pub fn get_vm_configuration() -> Result<Vec<u8>, String> {
+  get_buffer_bytes(BufferType::VmConfiguration, 0, usize::MAX)
+}
+
+pub fn get_plugin_configuration() -> Result<Vec<u8>, String> {
+  get_buffer_bytes(BufferType::PluginConfiguration, 0, usize::MAX)
+}
+// ====================== Buffer Processing API ===========================
+
+// ====================== Property API ===========================
+/// Reads a host property such as `["request", "path"]` or `["upstream", "address"]`. Returns
+/// `None` if the property does not exist.
+pub fn get_property(path: &[&str]) -> Result<Option<Vec<u8>>, String> {
+  payload_wrapper::get_property(path)
+}
+
+pub fn set_property(path: &[&str], value: &[u8]) -> WasmResult {
+  payload_wrapper::set_property(path, value)
+}
+// ====================== Property API ===========================
+
+// ====================== HTTP Types Interop API ===========================
+#[cfg(feature = "http-types")]
+pub fn get_request_parts() -> Result<http::request::Parts, String> {
+  request_parts_from_pairs(&get_request_header_pairs()?)
+}
+
+#[cfg(feature = "http-types")]
+pub fn set_request_parts(parts: &http::request::Parts) -> WasmResult {
+  match request_parts_into_pairs(parts) {
+    Ok(pairs) => auto_clear_route_cache(set_header_map_pair_list(
+      HeaderMapType::RequestHeaders,
+      &pairs,
+    )),
+    Err(e) => {
+      warn!("failed to convert request parts: {}", e);
+      WasmResult::BadArgument
+    }
+  }
+}
+
+#[cfg(feature = "http-types")]
+pub fn get_response_parts() -> Result<http::response::Parts, String> {
+  response_parts_from_pairs(&get_response_header_pairs()?)
+}
+
+#[cfg(feature = "http-types")]
+pub fn set_response_parts(parts: &http::response::Parts) -> WasmResult {
+  match response_parts_into_pairs(parts) {
+    Ok(pairs) => set_header_map_pair_list(HeaderMapType::ResponseHeaders, &pairs),
+    Err(e) => {
+      warn!("failed to convert response parts: {}", e);
+      WasmResult::BadArgument
+    }
+  }
+}
+
+#[cfg(feature = "http-types")]
+pub fn request_parts_from_pairs(
+  pairs: &HashMap<String, String>,
+) -> Result<http::request::Parts, String> {
+  let mut builder = http::Request::builder();
+  if let Some(method) = pairs.get(":method") {
+    builder = builder.method(method.as_str());
+  }
+  let path = pairs.get(":path").map(|p| p.as_str()).unwrap_or("/");
+  let uri = match (pairs.get(":scheme"), pairs.get(":authority")) {
+    (Some(scheme), Some(authority)) => http::Uri::builder()
+      .scheme(scheme.as_str())
+      .authority(authority.as_str())
+      .path_and_query(path)
+      .build(),
+    _ => http::Uri::builder().path_and_query(path).build(),
+  };
+  builder = builder.uri(uri.map_err(|e| e.to_string())?);
+  let (mut parts, _) = builder.body(()).map_err(|e| e.to_string())?.into_parts();
+  parts.headers = header_map_from_pairs(pairs)?;
+  Ok(parts)
+}
+
+#[cfg(feature = "http-types")]
+pub fn request_parts_into_pairs(
+  parts: &http::request::Parts,
+) -> Result<Vec<(String, String)>, String> {
+  let mut pairs = vec![(":method".to_string(), parts.method.as_str().to_string())];
+  let path = match parts.uri.path_and_query() {
+    Some(p) => p.as_str().to_string(),
+    None => "/".to_string(),
+  };
+  pairs.push((":path".to_string(), path));
+  if let Some(authority) = parts.uri.authority() {
+    pairs.push((":authority".to_string(), authority.as_str().to_string()));
+  }
+  if let Some(scheme) = parts.uri.scheme_str() {
+    pairs.push((":scheme".to_string(), scheme.to_string()));
+  }
+  pairs.extend(header_map_into_pairs(&parts.headers)?);
+  Ok(pairs)
+}
+
+#[cfg(feature = "http-types")]
+pub fn response_parts_from_pairs(
+  pairs: &HashMap<String, String>,
+) -> Result<http::response::Parts, String> {
+  let mut builder = http::Response::builder();
+  if let Some(status) = pairs.get(":status") {
+    builder = builder.status(status.as_str());
+  }
+  let (mut parts, _) = builder.body(()).map_err(|e| e.to_string())?.into_parts();
+  parts.headers = header_map_from_pairs(pairs)?;
+  Ok(parts)
+}
+
+#[cfg(feature = "http-types")]
+pub fn response_parts_into_pairs(
+  parts: &http::response::Parts,
+) -> Result<Vec<(String, String)>, String> {
+  let mut pairs = vec![(":status".to_string(), parts.status.as_str().to_string())];
+  pairs.extend(header_map_into_pairs(&parts.headers)?);
+  Ok(pairs)
+}
+
+#[cfg(feature = "http-types")]
+fn header_map_from_pairs(pairs: &HashMap<String, String>) -> Result<http::HeaderMap, String> {
+  let mut headers = http::HeaderMap::with_capacity(pairs.len());
+  for (k, v) in pairs {
+    if k.starts_with(':') {
+      continue;
+    }
+    let name = http::header::HeaderName::from_bytes(k.as_bytes()).map_err(|e| e.to_string())?;
+    let value = http::header::HeaderValue::from_str(v).map_err(|e| e.to_string())?;
+    headers.append(name, value);
+  }
+  Ok(headers)
+}
+
+// Repeated headers become one pair per value. Folding them would break `set-cookie`.
+#[cfg(feature = "http-types")]
+fn header_map_into_pairs(headers: &http::HeaderMap) -> Result<Vec<(String, String)>, String> {
+  let mut pairs = Vec::with_capacity(headers.len());
+  for (name, value) in headers.iter() {
+    let value = value.to_str().map_err(|e| e.to_string())?;
+    pairs.push((name.as_str().to_string(), value.to_string()));
+  }
+  Ok(pairs)
+}
+// ====================== HTTP Types Interop API ===========================
+
+#[cfg(all(test, feature = "http-types"))]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keeps_repeated_headers_apart() {
+    let (mut parts, _) = http::Response::builder()
+      .status(201)
+      .header("set-cookie", "a=1; Path=/")
+      .header("set-cookie", "b=2")
+      .header("content-type", "text/plain")
+      .body(())
+      .unwrap()
+      .into_parts();
+    let pairs = response_parts_into_pairs(&parts).unwrap();
+    assert_eq!(
+      pairs,
+      vec![
+        (":status".to_string(), "201".to_string()),
+        ("set-cookie".to_string(), "a=1; Path=/".to_string()),
+        ("set-cookie".to_string(), "b=2".to_string()),
+        ("content-type".to_string(), "text/plain".to_string()),
+      ]
+    );
+    parts.headers.clear();
+    assert_eq!(response_parts_into_pairs(&parts).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn request_pairs_start_with_pseudo_headers() {
+    let (parts, _) = http::Request::builder()
+      .method("POST")
+      .uri("https://example.com/a?b=c")
+      .header("accept", "*/*")
+      .body(())
+      .unwrap()
+      .into_parts();
+    assert_eq!(
+      request_parts_into_pairs(&parts).unwrap(),
+      vec![
+        (":method".to_string(), "POST".to_string()),
+        (":path".to_string(), "/a?b=c".to_string()),
+        (":authority".to_string(), "example.com".to_string()),
+        (":scheme".to_string(), "https".to_string()),
+        ("accept".to_string(), "*/*".to_string()),
+      ]
+    );
+  }
+}
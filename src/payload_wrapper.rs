@@ -54,6 +54,43 @@ pub fn set_header_map_pairs(htype: HeaderMapType, _pairs: &HashMap<String, Strin
   }
 }
 
+/// Like `get_header_map_pairs`, keeping the order and every value of repeated headers.
+pub fn get_header_map_pair_list(htype: HeaderMapType) -> Result<Vec<(String, String)>, String> {
+  let type_num = header_map_type_to_int(htype);
+  let data_ptr: *mut c_char = null_mut::<c_char>();
+  let mut size: usize = 0;
+  unsafe {
+    let code = proxy_get_header_map_pairs(type_num, &data_ptr, &mut size);
+    match WasmResult::try_from(code) {
+      Ok(WasmResult::Ok) => {
+        if data_ptr.is_null() || size == 0 {
+          return Ok(Vec::new());
+        }
+        let buffer = Vec::from_raw_parts(data_ptr as *mut u8, size, size);
+        bytes_into_pairs(&buffer).ok_or_else(|| "malformed header map".to_string())
+      }
+      Ok(r) => Err(r.to_string()),
+      Err(e) => Err(e),
+    }
+  }
+}
+
+/// Like `set_header_map_pairs`, keeping the order of `pairs` and every value of repeated headers.
+pub fn set_header_map_pair_list(htype: HeaderMapType, pairs: &[(String, String)]) -> WasmResult {
+  let type_num = header_map_type_to_int(htype);
+  let buffer = pairs_into_bytes(pairs);
+  unsafe {
+    let code = proxy_set_header_map_pairs(type_num, buffer.as_ptr() as *const c_char, buffer.len());
+    match WasmResult::try_from(code) {
+      Ok(r) => r,
+      Err(e) => {
+        warn!("failed to convert: {}", e);
+        WasmResult::InternalFailure
+      }
+    }
+  }
+}
+
 pub fn get_header_map_value(htype: HeaderMapType, key: String) -> Result<Box<WasmData>, String> {
   let type_num = header_map_type_to_int(htype);
   let data_ptr: *mut c_char = null_mut::<c_char>();
@@ -134,4 +171,106 @@ pub fn get_header_map_value_size(htype: HeaderMapType) -> u32 {
   unsafe { proxy_get_header_map_size(type_num, size_ptr) }
 }
 
+pub fn get_buffer_bytes(btype: BufferType, start: usize, length: usize) -> Result<Vec<u8>, String> {
+  let type_num = buffer_type_to_int(btype);
+  let data_ptr: *mut c_char = null_mut::<c_char>();
+  let mut size: usize = 0;
+  unsafe {
+    let code = proxy_get_buffer_bytes(type_num, start, length, &data_ptr, &mut size);
+    match WasmResult::try_from(code) {
+      Ok(r) => match r {
+        WasmResult::Ok => {
+          if data_ptr.is_null() || size == 0 {
+            Ok(Vec::new())
+          } else {
+            Ok(Vec::from_raw_parts(data_ptr as *mut u8, size, size))
+          }
+        }
+        _ => Err(r.to_string()),
+      },
+      Err(e) => Err(e),
+    }
+  }
+}
+
+pub fn set_buffer_bytes(btype: BufferType, start: usize, length: usize, data: &[u8]) -> WasmResult {
+  let type_num = buffer_type_to_int(btype);
+  unsafe {
+    let code = proxy_set_buffer_bytes(
+      type_num,
+      start,
+      length,
+      data.as_ptr() as *const c_char,
+      data.len(),
+    );
+    match WasmResult::try_from(code) {
+      Ok(r) => r,
+      Err(e) => {
+        warn!("failed to convert: {}", e);
+        WasmResult::InternalFailure
+      }
+    }
+  }
+}
+
+/// Reads the property at `path`, whose segments are joined with `\0`. Returns `None` if the
+/// property does not exist.
+pub fn get_property(path: &[&str]) -> Result<Option<Vec<u8>>, String> {
+  let path = path.join("\0");
+  let data_ptr: *mut c_char = null_mut::<c_char>();
+  let mut size: usize = 0;
+  unsafe {
+    let code = proxy_get_property(
+      path.as_ptr() as *const c_char,
+      path.len(),
+      &data_ptr,
+      &mut size,
+    );
+    match WasmResult::try_from(code) {
+      Ok(WasmResult::Ok) => {
+        if data_ptr.is_null() || size == 0 {
+          Ok(Some(Vec::new()))
+        } else {
+          Ok(Some(Vec::from_raw_parts(data_ptr as *mut u8, size, size)))
+        }
+      }
+      Ok(WasmResult::NotFound) => Ok(None),
+      Ok(r) => Err(r.to_string()),
+      Err(e) => Err(e),
+    }
+  }
+}
+
+pub fn set_property(path: &[&str], value: &[u8]) -> WasmResult {
+  let path = path.join("\0");
+  unsafe {
+    let code = proxy_set_property(
+      path.as_ptr() as *const c_char,
+      path.len(),
+      value.as_ptr() as *const c_char,
+      value.len(),
+    );
+    match WasmResult::try_from(code) {
+      Ok(r) => r,
+      Err(e) => {
+        warn!("failed to convert: {}", e);
+        WasmResult::InternalFailure
+      }
+    }
+  }
+}
+
+pub fn clear_route_cache() -> WasmResult {
+  unsafe {
+    let code = proxy_clear_route_cache();
+    match WasmResult::try_from(code) {
+      Ok(r) => r,
+      Err(e) => {
+        warn!("failed to convert: {}", e);
+        WasmResult::InternalFailure
+      }
+    }
+  }
+}
+
 // ======================= Low-Level Proxy API Wrapper =============================
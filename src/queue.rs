@@ -0,0 +1,66 @@
+use crate::host::*;
+use crate::types::*;
+use std::os::raw::c_char;
+use std::ptr::null_mut;
+
+/// Registers a queue owned by the current VM. `on_queue_ready` of the calling root context is
+/// invoked whenever data is enqueued.
+pub fn register_shared_queue(name: &str) -> Result<u32, String> {
+  let mut queue_id: u32 = 0;
+  unsafe {
+    match to_wasm_result(proxy_register_shared_queue(
+      name.as_ptr() as *const c_char,
+      name.len(),
+      &mut queue_id,
+    )) {
+      WasmResult::Ok => Ok(queue_id),
+      r => Err(r.to_string()),
+    }
+  }
+}
+
+/// Looks up a queue registered by the VM identified by `vm_id`.
+pub fn resolve_shared_queue(vm_id: &str, name: &str) -> Result<u32, String> {
+  let mut queue_id: u32 = 0;
+  unsafe {
+    match to_wasm_result(proxy_resolve_shared_queue(
+      vm_id.as_ptr() as *const c_char,
+      vm_id.len(),
+      name.as_ptr() as *const c_char,
+      name.len(),
+      &mut queue_id,
+    )) {
+      WasmResult::Ok => Ok(queue_id),
+      r => Err(r.to_string()),
+    }
+  }
+}
+
+pub fn enqueue_shared_queue(queue_id: u32, data: &[u8]) -> WasmResult {
+  unsafe {
+    to_wasm_result(proxy_enqueue_shared_queue(
+      queue_id,
+      data.as_ptr() as *const c_char,
+      data.len(),
+    ))
+  }
+}
+
+/// Pops the oldest item of the queue, or returns `None` if the queue is empty.
+pub fn dequeue_shared_queue(queue_id: u32) -> Result<Option<Vec<u8>>, String> {
+  let data_ptr: *mut c_char = null_mut::<c_char>();
+  let mut size: usize = 0;
+  unsafe {
+    match to_wasm_result(proxy_dequeue_shared_queue(queue_id, &data_ptr, &mut size)) {
+      WasmResult::Ok => {
+        if data_ptr.is_null() || size == 0 {
+          Ok(Some(Vec::new()))
+        } else {
+          Ok(Some(Vec::from_raw_parts(data_ptr as *mut u8, size, size)))
+        }
+      }
+      WasmResult::Empty => Ok(None),
+      r => Err(r.to_string()),
+    }
+  }
+}
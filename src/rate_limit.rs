@@ -0,0 +1,404 @@
+use crate::extensions::{get_extension, insert_extension};
+use crate::payload::*;
+use crate::reply::LocalReply;
+use crate::shared_data::update_shared_data;
+use crate::time::current_time_nanos;
+use crate::types::*;
+use log::warn;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// Token buckets count thousandths of tokens, so that slow refill rates are not rounded away.
+const MILLI: u64 = 1000;
+const DEFAULT_KEY_SLOTS: u64 = 65536;
+
+/// Request attribute a limit is keyed by.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RateLimitKey {
+  /// Value of a request header. Requests without the header are not limited.
+  ///
+  /// Header values are chosen by clients, who could create any number of limits. Limits keyed by
+  /// `Header` or `SourceIp` are therefore hashed into a fixed number of slots, see
+  /// `RateLimiter::key_slots`.
+  Header(String),
+  /// Downstream address, without the port.
+  SourceIp,
+  /// Requests whose path starts with the prefix share one limit. Other requests are not
+  /// limited.
+  PathPrefix(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RateLimitAlgorithm {
+  /// Allows bursts of `capacity` requests, refilled at `refill_per_second`.
+  TokenBucket {
+    capacity: u64,
+    refill_per_second: u64,
+  },
+  /// Allows `limit` requests per `window`.
+  FixedWindow { limit: u64, window: Duration },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimitDecision {
+  pub allowed: bool,
+  pub limit: u64,
+  pub remaining: u64,
+  /// Time until the limit is fully available again.
+  pub reset: Duration,
+}
+
+impl RateLimitDecision {
+  /// `x-ratelimit-limit`, `x-ratelimit-remaining` and `x-ratelimit-reset`, in seconds.
+  pub fn headers(&self) -> Vec<(String, String)> {
+    vec![
+      ("x-ratelimit-limit".to_string(), self.limit.to_string()),
+      (
+        "x-ratelimit-remaining".to_string(),
+        self.remaining.to_string(),
+      ),
+      (
+        "x-ratelimit-reset".to_string(),
+        self.reset_seconds().to_string(),
+      ),
+    ]
+  }
+
+  fn reset_seconds(&self) -> u64 {
+    (self.reset.as_nanos() as u64).div_ceil(NANOS_PER_SECOND)
+  }
+
+  /// 429 reply carrying the rate limit headers and `retry-after`.
+  pub fn reply(&self) -> LocalReply {
+    let mut reply = LocalReply::too_many_requests()
+      .details("rate_limited")
+      .header("retry-after", &self.reset_seconds().max(1).to_string());
+    for (key, value) in self.headers() {
+      reply = reply.header(&key, &value);
+    }
+    reply
+  }
+}
+
+/// Local rate limiter whose state lives in shared data, so that it is enforced across all VMs
+/// with the same vm_id.
+pub struct RateLimiter {
+  name: String,
+  algorithm: RateLimitAlgorithm,
+  keys: Vec<RateLimitKey>,
+  key_slots: u64,
+}
+
+fn request_header(key: &str) -> Option<String> {
+  match get_request_header(key.to_string()).map(|v| v.to_string()) {
+    Ok(v) if !v.is_empty() => Some(v),
+    _ => None,
+  }
+}
+
+fn source_ip() -> Option<String> {
+  let address = get_property(&["source", "address"]).ok()??;
+  let address = String::from_utf8_lossy(&address).to_string();
+  // `1.2.3.4:5678` or `[::1]:5678`
+  let ip = match address.rfind(':') {
+    Some(i) if address.starts_with('[') || address.matches(':').count() == 1 => &address[..i],
+    _ => address.as_str(),
+  };
+  Some(ip.trim_start_matches('[').trim_end_matches(']').to_string())
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+  data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+    (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+  })
+}
+
+fn read_u64_pair(state: Option<&[u8]>) -> Option<(u64, u64)> {
+  let state = state?;
+  if state.len() != 16 {
+    return None;
+  }
+  let first = u64::from_le_bytes(<[u8; 8]>::try_from(&state[..8]).ok()?);
+  let second = u64::from_le_bytes(<[u8; 8]>::try_from(&state[8..]).ok()?);
+  Some((first, second))
+}
+
+fn write_u64_pair(first: u64, second: u64) -> Vec<u8> {
+  let mut state = first.to_le_bytes().to_vec();
+  state.extend_from_slice(&second.to_le_bytes());
+  state
+}
+
+impl RateLimiter {
+  /// `name` tells limiters apart in shared data.
+  pub fn new(name: &str, algorithm: RateLimitAlgorithm) -> RateLimiter {
+    RateLimiter {
+      name: name.to_string(),
+      algorithm,
+      keys: Vec::new(),
+      key_slots: DEFAULT_KEY_SLOTS,
+    }
+  }
+
+  /// Adds an attribute to the key. Without keys, all requests share one limit.
+  pub fn key(mut self, key: RateLimitKey) -> RateLimiter {
+    self.keys.push(key);
+    self
+  }
+
+  /// Number of shared data entries limits keyed by `Header` or `SourceIp` are spread over,
+  /// 65536 by default. Shared data entries are never removed, so this bounds the memory used by
+  /// the limiter. Requests whose keys fall in the same slot share a limit.
+  pub fn key_slots(mut self, key_slots: u64) -> RateLimiter {
+    self.key_slots = std::cmp::max(key_slots, 1);
+    self
+  }
+
+  fn request_key(&self) -> Option<String> {
+    let mut parts = Vec::new();
+    for key in &self.keys {
+      let part = match key {
+        RateLimitKey::Header(name) => request_header(name)?,
+        RateLimitKey::SourceIp => source_ip()?,
+        RateLimitKey::PathPrefix(prefix) => match request_header(":path") {
+          Some(path) if path.starts_with(prefix.as_str()) => prefix.clone(),
+          _ => return None,
+        },
+      };
+      parts.push(part);
+    }
+    let unbounded = self
+      .keys
+      .iter()
+      .any(|k| matches!(k, RateLimitKey::Header(_) | RateLimitKey::SourceIp));
+    if unbounded {
+      let slot = fnv1a(parts.join("|").as_bytes()) % self.key_slots;
+      parts = vec![format!("{:x}", slot)];
+    }
+    parts.insert(0, self.name.clone());
+    parts.insert(0, "ratelimit".to_string());
+    Some(parts.join("|"))
+  }
+
+  /// Counts the current request against its limit. Returns `None` if the request is not
+  /// subject to the limit. Errors of the host fail open.
+  pub fn check(&self) -> Option<RateLimitDecision> {
+    let key = self.request_key()?;
+    let now = current_time_nanos();
+    let algorithm = self.algorithm;
+    let decision = update_shared_data(&key, |state| match algorithm {
+      RateLimitAlgorithm::TokenBucket {
+        capacity,
+        refill_per_second,
+      } => RateLimiter::take_token(state, now, capacity, refill_per_second),
+      RateLimitAlgorithm::FixedWindow { limit, window } => {
+        RateLimiter::count_in_window(state, now, limit, window)
+      }
+    });
+    match decision {
+      Ok(decision) => Some(decision),
+      Err(e) => {
+        warn!("failed to update rate limit {}: {}", key, e);
+        None
+      }
+    }
+  }
+
+  fn take_token(
+    state: Option<&[u8]>,
+    now: u64,
+    capacity: u64,
+    refill_per_second: u64,
+  ) -> (Vec<u8>, RateLimitDecision) {
+    let full = capacity.saturating_mul(MILLI);
+    let (tokens, last) = read_u64_pair(state).unwrap_or((full, now));
+    let elapsed = now.saturating_sub(last) as u128;
+    let rate = refill_per_second as u128 * MILLI as u128;
+    let refill = elapsed * rate / NANOS_PER_SECOND as u128;
+    let mut tokens = std::cmp::min(full as u128, tokens as u128 + refill) as u64;
+    // Only the time standing for whole refilled thousandths is consumed, so frequent checks do
+    // not discard the remainder. A full bucket does not bank time.
+    let last = match rate {
+      0 => now,
+      _ if tokens == full => now,
+      rate => last + (refill * NANOS_PER_SECOND as u128 / rate) as u64,
+    };
+    let allowed = tokens >= MILLI;
+    if allowed {
+      tokens -= MILLI;
+    }
+    let reset = match refill_per_second {
+      0 => Duration::from_secs(0),
+      rate => Duration::from_nanos(
+        ((full - tokens) as u128 * NANOS_PER_SECOND as u128 / (rate as u128 * MILLI as u128))
+          as u64,
+      ),
+    };
+    let decision = RateLimitDecision {
+      allowed,
+      limit: capacity,
+      remaining: tokens / MILLI,
+      reset,
+    };
+    (write_u64_pair(tokens, last), decision)
+  }
+
+  fn count_in_window(
+    state: Option<&[u8]>,
+    now: u64,
+    limit: u64,
+    window: Duration,
+  ) -> (Vec<u8>, RateLimitDecision) {
+    let window = std::cmp::max(window.as_nanos() as u64, 1);
+    let start = now - now % window;
+    let count = match read_u64_pair(state) {
+      Some((window_start, count)) if window_start == start => count,
+      _ => 0,
+    };
+    let allowed = count < limit;
+    let count = if allowed { count + 1 } else { count };
+    let decision = RateLimitDecision {
+      allowed,
+      limit,
+      remaining: limit.saturating_sub(count),
+      reset: Duration::from_nanos(start + window - now),
+    };
+    (write_u64_pair(start, count), decision)
+  }
+
+  /// Checks the request from `on_request_headers` and answers it with 429 when the limit is
+  /// exceeded. The decision is kept in the extensions of the stream for
+  /// `add_rate_limit_response_headers`.
+  pub fn enforce(&self) -> FilterHeadersStatus {
+    let decision = match self.check() {
+      Some(decision) => decision,
+      None => return FilterHeadersStatus::Continue,
+    };
+    let allowed = decision.allowed;
+    if !allowed {
+      match decision.reply().send() {
+        WasmResult::Ok => {}
+        r => warn!("failed to send local reply: {}", r),
+      }
+    }
+    insert_extension(decision);
+    match allowed {
+      true => FilterHeadersStatus::Continue,
+      false => FilterHeadersStatus::StopIteration,
+    }
+  }
+}
+
+/// Adds the rate limit headers of the decision made by `RateLimiter::enforce`, if any, to the
+/// response. Called from `on_response_headers`.
+pub fn add_rate_limit_response_headers() {
+  // Denied requests were answered with a reply which carries the headers already.
+  let decision = match get_extension::<RateLimitDecision>() {
+    Some(decision) if decision.allowed => decision,
+    _ => return,
+  };
+  for (key, value) in decision.headers() {
+    match add_response_header(key, value) {
+      WasmResult::Ok => {}
+      r => warn!("failed to add rate limit header: {}", r),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MILLISECOND: u64 = 1_000_000;
+
+  #[test]
+  fn token_bucket_refills_under_frequent_checks() {
+    // One request per second, checked every 100µs: the remainder of each refill must add up.
+    let mut state: Option<Vec<u8>> = None;
+    let mut allowed_at = Vec::new();
+    for i in 0..25_000u64 {
+      let now = 1_000 * NANOS_PER_SECOND + i * MILLISECOND / 10;
+      let (next, decision) = RateLimiter::take_token(state.as_deref(), now, 1, 1);
+      if decision.allowed {
+        allowed_at.push(now - 1_000 * NANOS_PER_SECOND);
+      }
+      state = Some(next);
+    }
+    assert_eq!(allowed_at, vec![0, NANOS_PER_SECOND, 2 * NANOS_PER_SECOND]);
+  }
+
+  #[test]
+  fn token_bucket_does_not_bank_time_when_full() {
+    let start = 1_000 * NANOS_PER_SECOND;
+    let (state, _) = RateLimiter::take_token(None, start, 2, 1);
+    let (state, decision) =
+      RateLimiter::take_token(Some(&state), start + 60 * NANOS_PER_SECOND, 2, 1);
+    assert!(decision.allowed);
+    assert_eq!(decision.remaining, 1);
+    let (state, decision) =
+      RateLimiter::take_token(Some(&state), start + 60 * NANOS_PER_SECOND, 2, 1);
+    assert!(decision.allowed);
+    assert_eq!(decision.remaining, 0);
+    let (_, decision) = RateLimiter::take_token(Some(&state), start + 60 * NANOS_PER_SECOND, 2, 1);
+    assert!(!decision.allowed);
+    assert_eq!(decision.reset, Duration::from_secs(2));
+  }
+
+  #[test]
+  fn fixed_window_resets_at_the_window_boundary() {
+    let window = Duration::from_secs(10);
+    let start = 1_000 * NANOS_PER_SECOND;
+    let (state, decision) = RateLimiter::count_in_window(None, start + 1, 2, window);
+    assert!(decision.allowed);
+    assert_eq!(
+      decision.reset,
+      Duration::from_nanos(10 * NANOS_PER_SECOND - 1)
+    );
+    let (state, _) = RateLimiter::count_in_window(Some(&state), start + 2, 2, window);
+    let (state, decision) = RateLimiter::count_in_window(Some(&state), start + 3, 2, window);
+    assert!(!decision.allowed);
+    assert_eq!(decision.remaining, 0);
+    let (_, decision) =
+      RateLimiter::count_in_window(Some(&state), start + 10 * NANOS_PER_SECOND, 2, window);
+    assert!(decision.allowed);
+    assert_eq!(decision.remaining, 1);
+  }
+
+  #[test]
+  fn fixed_window_tolerates_counts_above_the_limit() {
+    // The limit was lowered by a reconfiguration while the window was running.
+    let window = Duration::from_secs(10);
+    let start = 1_000 * NANOS_PER_SECOND;
+    let (state, _) = RateLimiter::count_in_window(None, start, 5, window);
+    let (state, _) = RateLimiter::count_in_window(Some(&state), start + 1, 5, window);
+    let (state, _) = RateLimiter::count_in_window(Some(&state), start + 2, 5, window);
+    let (_, decision) = RateLimiter::count_in_window(Some(&state), start + 3, 2, window);
+    assert!(!decision.allowed);
+    assert_eq!(decision.remaining, 0);
+  }
+
+  #[test]
+  fn decision_headers_round_up_the_reset() {
+    let decision = RateLimitDecision {
+      allowed: false,
+      limit: 10,
+      remaining: 0,
+      reset: Duration::from_millis(1500),
+    };
+    assert_eq!(
+      decision.headers(),
+      vec![
+        ("x-ratelimit-limit".to_string(), "10".to_string()),
+        ("x-ratelimit-remaining".to_string(), "0".to_string()),
+        ("x-ratelimit-reset".to_string(), "2".to_string()),
+      ]
+    );
+  }
+
+  #[test]
+  fn fnv1a_matches_reference_values() {
+    assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+  }
+}
@@ -1,9 +1,22 @@
 use crate::buffer::*;
+use crate::encoding::*;
 use crate::host::*;
+use crate::payload::*;
 use crate::types::*;
 use log::warn;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::os::raw::c_char;
+use std::ptr::null;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+static LOCAL_REPLY_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Number of local replies sent so far by this VM. Comparing it before and after a call tells
+/// whether the callee sent a local reply.
+pub fn local_reply_count() -> u32 {
+  LOCAL_REPLY_COUNT.load(Ordering::Relaxed)
+}
 
 pub fn send_local_response(
   status_code: u32,
@@ -13,6 +26,7 @@ pub fn send_local_response(
   grpc_status: GrpcStatus,
 ) -> WasmResult {
   let (buffer_ptr, size) = export_hashmap(additional_header);
+  LOCAL_REPLY_COUNT.fetch_add(1, Ordering::Relaxed);
   unsafe {
     let code = proxy_send_local_response(
       status_code,
@@ -33,3 +47,254 @@ pub fn send_local_response(
     }
   }
 }
+
+/// Sends a local response whose body may be binary and whose headers keep their order and
+/// duplicated keys.
+pub(crate) fn send_local_response_bytes(
+  status_code: u32,
+  details: &str,
+  body: &[u8],
+  additional_header_pairs: &[(String, String)],
+  grpc_status: GrpcStatus,
+) -> WasmResult {
+  let header_buffer = pairs_into_bytes(additional_header_pairs);
+  let header_ptr = if header_buffer.is_empty() {
+    null::<c_char>()
+  } else {
+    header_buffer.as_ptr() as *const c_char
+  };
+  LOCAL_REPLY_COUNT.fetch_add(1, Ordering::Relaxed);
+  unsafe {
+    let code = proxy_send_local_response(
+      status_code,
+      details.as_ptr() as *const c_char,
+      details.len(),
+      body.as_ptr() as *const c_char,
+      body.len(),
+      header_ptr,
+      header_buffer.len(),
+      grpc_status_to_int(grpc_status),
+    );
+    match WasmResult::try_from(code) {
+      Ok(r) => r,
+      Err(e) => {
+        warn!("failed to convert: {}", e);
+        WasmResult::InternalFailure
+      }
+    }
+  }
+}
+
+#[cfg(feature = "http-types")]
+pub fn send_http_response(response: http::Response<Vec<u8>>) -> WasmResult {
+  let (parts, body) = response.into_parts();
+  let mut pairs = Vec::with_capacity(parts.headers.len());
+  for (name, value) in parts.headers.iter() {
+    match value.to_str() {
+      Ok(v) => pairs.push((name.as_str().to_string(), v.to_string())),
+      Err(_) => {
+        warn!("header {} is not a visible ASCII string", name);
+        return WasmResult::BadArgument;
+      }
+    }
+  }
+  send_local_response_bytes(
+    parts.status.as_u16() as u32,
+    "",
+    &body,
+    &pairs,
+    GrpcStatus::InvalidCode,
+  )
+}
+
+// ====================== Local Reply Builder ===========================
+/// Builder for local responses with binary bodies and ordered, multi-valued headers.
+pub struct LocalReply {
+  status_code: u32,
+  details: String,
+  body: Vec<u8>,
+  headers: Vec<(String, String)>,
+  grpc_status: GrpcStatus,
+}
+
+impl LocalReply {
+  pub fn new(status_code: u32) -> LocalReply {
+    LocalReply {
+      status_code,
+      details: String::new(),
+      body: Vec::new(),
+      headers: Vec::new(),
+      grpc_status: GrpcStatus::InvalidCode,
+    }
+  }
+
+  pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> LocalReply {
+    self.body = body.into();
+    self
+  }
+
+  pub fn text(self, body: &str) -> LocalReply {
+    self.content_type("text/plain; charset=utf-8").body(body)
+  }
+
+  pub fn html(self, body: &str) -> LocalReply {
+    self.content_type("text/html; charset=utf-8").body(body)
+  }
+
+  /// Sets an already serialized JSON document as the body.
+  pub fn json(self, body: &str) -> LocalReply {
+    self.content_type("application/json").body(body)
+  }
+
+  /// Replaces any previously set `content-type`.
+  pub fn content_type(self, content_type: &str) -> LocalReply {
+    self.set_header("content-type", content_type)
+  }
+
+  /// Appends a header. Calling it several times with the same key sends all values.
+  pub fn header(mut self, key: &str, value: &str) -> LocalReply {
+    self
+      .headers
+      .push((key.to_ascii_lowercase(), value.to_string()));
+    self
+  }
+
+  /// Replaces every value of a header.
+  pub fn set_header(mut self, key: &str, value: &str) -> LocalReply {
+    let key = key.to_ascii_lowercase();
+    self.headers.retain(|(k, _)| *k != key);
+    self.headers.push((key, value.to_string()));
+    self
+  }
+
+  /// Sets the response code details reported in access logs, e.g. `%RESPONSE_CODE_DETAILS%`.
+  pub fn details(mut self, details: &str) -> LocalReply {
+    self.details = details.to_string();
+    self
+  }
+
+  pub fn grpc_status(mut self, grpc_status: GrpcStatus) -> LocalReply {
+    self.grpc_status = grpc_status;
+    self
+  }
+
+  pub fn grpc_message(self, message: &str) -> LocalReply {
+    self.set_header("grpc-message", &grpc_message_encode(message))
+  }
+
+  /// Trailers-only gRPC response carrying `status`, `message` and, if given, a serialized
+  /// `google.rpc.Status` in `grpc-status-details-bin`.
+  pub fn grpc(status: GrpcStatus, message: &str, details: Option<&[u8]>) -> LocalReply {
+    let mut reply = LocalReply::new(200)
+      .grpc_status(status)
+      .grpc_message(message);
+    if let Some(details) = details {
+      reply = reply.set_header("grpc-status-details-bin", &base64_encode(details));
+    }
+    // Envoy writes content-type and grpc-status itself when the request is gRPC. Otherwise
+    // they have to be part of the reply for it to be understood by gRPC clients.
+    if !is_grpc_request() {
+      reply = reply
+        .content_type("application/grpc")
+        .set_header("grpc-status", &grpc_status_to_int(status).to_string());
+    }
+    reply
+  }
+
+  pub fn send(self) -> WasmResult {
+    send_local_response_bytes(
+      self.status_code,
+      &self.details,
+      &self.body,
+      &self.headers,
+      self.grpc_status,
+    )
+  }
+
+  /// Minimal HTML error page for the given status code.
+  pub fn error_page(status_code: u32) -> LocalReply {
+    let title = format!("{} {}", status_code, reason_phrase(status_code));
+    LocalReply::new(status_code).html(&format!(
+      "<html><head><title>{0}</title></head><body><h1>{0}</h1></body></html>\n",
+      title
+    ))
+  }
+
+  pub fn bad_request() -> LocalReply {
+    LocalReply::error_page(400)
+  }
+
+  pub fn unauthorized() -> LocalReply {
+    LocalReply::error_page(401)
+  }
+
+  pub fn forbidden() -> LocalReply {
+    LocalReply::error_page(403)
+  }
+
+  pub fn not_found() -> LocalReply {
+    LocalReply::error_page(404)
+  }
+
+  pub fn method_not_allowed() -> LocalReply {
+    LocalReply::error_page(405)
+  }
+
+  pub fn payload_too_large() -> LocalReply {
+    LocalReply::error_page(413)
+  }
+
+  pub fn too_many_requests() -> LocalReply {
+    LocalReply::error_page(429)
+  }
+
+  pub fn internal_server_error() -> LocalReply {
+    LocalReply::error_page(500)
+  }
+
+  pub fn service_unavailable() -> LocalReply {
+    LocalReply::error_page(503)
+  }
+}
+
+pub fn grpc_error(status: GrpcStatus, message: &str, details: Option<&[u8]>) -> WasmResult {
+  LocalReply::grpc(status, message, details).send()
+}
+
+fn is_grpc_request() -> bool {
+  match get_request_header("content-type".to_string()) {
+    Ok(v) => v.to_string().starts_with("application/grpc"),
+    Err(_) => false,
+  }
+}
+
+pub fn reason_phrase(status_code: u32) -> &'static str {
+  match status_code {
+    200 => "OK",
+    201 => "Created",
+    204 => "No Content",
+    301 => "Moved Permanently",
+    302 => "Found",
+    304 => "Not Modified",
+    307 => "Temporary Redirect",
+    308 => "Permanent Redirect",
+    400 => "Bad Request",
+    401 => "Unauthorized",
+    403 => "Forbidden",
+    404 => "Not Found",
+    405 => "Method Not Allowed",
+    408 => "Request Timeout",
+    409 => "Conflict",
+    411 => "Length Required",
+    413 => "Payload Too Large",
+    415 => "Unsupported Media Type",
+    429 => "Too Many Requests",
+    500 => "Internal Server Error",
+    501 => "Not Implemented",
+    502 => "Bad Gateway",
+    503 => "Service Unavailable",
+    504 => "Gateway Timeout",
+    _ => "Unknown",
+  }
+}
+// ====================== Local Reply Builder ===========================
@@ -0,0 +1,262 @@
+use crate::encoding::*;
+use crate::payload::*;
+use crate::payload_wrapper::{get_header_map_pair_list, set_header_map_pair_list};
+use crate::types::*;
+use log::warn;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Method {
+  Get,
+  Head,
+  Post,
+  Put,
+  Delete,
+  Connect,
+  Options,
+  Trace,
+  Patch,
+  Other(String),
+}
+
+impl Method {
+  pub fn as_str(&self) -> &str {
+    match self {
+      Method::Get => "GET",
+      Method::Head => "HEAD",
+      Method::Post => "POST",
+      Method::Put => "PUT",
+      Method::Delete => "DELETE",
+      Method::Connect => "CONNECT",
+      Method::Options => "OPTIONS",
+      Method::Trace => "TRACE",
+      Method::Patch => "PATCH",
+      Method::Other(m) => m,
+    }
+  }
+}
+
+impl From<&str> for Method {
+  fn from(method: &str) -> Self {
+    match method {
+      "GET" => Method::Get,
+      "HEAD" => Method::Head,
+      "POST" => Method::Post,
+      "PUT" => Method::Put,
+      "DELETE" => Method::Delete,
+      "CONNECT" => Method::Connect,
+      "OPTIONS" => Method::Options,
+      "TRACE" => Method::Trace,
+      "PATCH" => Method::Patch,
+      other => Method::Other(other.to_string()),
+    }
+  }
+}
+
+impl fmt::Display for Method {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.as_str())
+  }
+}
+
+/// Parsed view of the request pseudo-headers. Setters only update this view; `commit` writes
+/// the modified pseudo-headers back to the host and clears the route cache.
+pub struct RequestHead {
+  method: Method,
+  scheme: String,
+  authority: String,
+  path: String,
+  query: Option<String>,
+  method_modified: bool,
+  scheme_modified: bool,
+  authority_modified: bool,
+  path_modified: bool,
+}
+
+fn pseudo_header(key: &str) -> String {
+  match get_request_header(key.to_string()) {
+    Ok(v) => v.to_string(),
+    Err(_) => String::new(),
+  }
+}
+
+impl RequestHead {
+  pub fn load() -> Result<RequestHead, String> {
+    let method = pseudo_header(":method");
+    if method.is_empty() {
+      return Err("request has no :method".to_string());
+    }
+    let path = pseudo_header(":path");
+    let (path, query) = match path.find('?') {
+      Some(i) => (path[..i].to_string(), Some(path[i + 1..].to_string())),
+      None => (path, None),
+    };
+    Ok(RequestHead {
+      method: Method::from(method.as_str()),
+      scheme: pseudo_header(":scheme"),
+      authority: pseudo_header(":authority"),
+      path,
+      query,
+      method_modified: false,
+      scheme_modified: false,
+      authority_modified: false,
+      path_modified: false,
+    })
+  }
+
+  pub fn method(&self) -> &Method {
+    &self.method
+  }
+
+  pub fn scheme(&self) -> &str {
+    &self.scheme
+  }
+
+  pub fn authority(&self) -> &str {
+    &self.authority
+  }
+
+  /// Path without the query string.
+  pub fn path(&self) -> &str {
+    &self.path
+  }
+
+  pub fn raw_query(&self) -> Option<&str> {
+    self.query.as_deref()
+  }
+
+  /// Decoded query parameters in their original order.
+  pub fn query_params(&self) -> Vec<(String, String)> {
+    let query = match &self.query {
+      Some(q) => q,
+      None => return Vec::new(),
+    };
+    query
+      .split('&')
+      .filter(|p| !p.is_empty())
+      .map(|p| match p.find('=') {
+        Some(i) => (
+          percent_decode(&p[..i], true),
+          percent_decode(&p[i + 1..], true),
+        ),
+        None => (percent_decode(p, true), String::new()),
+      })
+      .collect()
+  }
+
+  pub fn query_param(&self, key: &str) -> Option<String> {
+    self
+      .query_params()
+      .into_iter()
+      .find(|(k, _)| k == key)
+      .map(|(_, v)| v)
+  }
+
+  /// Decoded, non-empty path segments.
+  pub fn path_segments(&self) -> Vec<String> {
+    self
+      .path
+      .split('/')
+      .filter(|s| !s.is_empty())
+      .map(|s| percent_decode(s, false))
+      .collect()
+  }
+
+  pub fn set_method(&mut self, method: Method) {
+    self.method = method;
+    self.method_modified = true;
+  }
+
+  pub fn set_scheme(&mut self, scheme: &str) {
+    self.scheme = scheme.to_string();
+    self.scheme_modified = true;
+  }
+
+  pub fn set_authority(&mut self, authority: &str) {
+    self.authority = authority.to_string();
+    self.authority_modified = true;
+  }
+
+  /// Replaces the path and keeps the current query string.
+  pub fn set_path(&mut self, path: &str) {
+    self.path = path.to_string();
+    self.path_modified = true;
+  }
+
+  pub fn set_raw_query(&mut self, query: Option<&str>) {
+    self.query = query.map(|q| q.to_string());
+    self.path_modified = true;
+  }
+
+  pub fn set_query_params(&mut self, params: &[(String, String)]) {
+    if params.is_empty() {
+      self.set_raw_query(None);
+      return;
+    }
+    let query = params
+      .iter()
+      .map(|(k, v)| format!("{}={}", percent_encode(k), percent_encode(v)))
+      .collect::<Vec<String>>()
+      .join("&");
+    self.set_raw_query(Some(&query));
+  }
+
+  pub fn path_and_query(&self) -> String {
+    match &self.query {
+      Some(q) => format!("{}?{}", self.path, q),
+      None => self.path.clone(),
+    }
+  }
+
+  /// Writes modified pseudo-headers to the host and clears the route cache so the new values
+  /// take part in routing. Does nothing if no setter was called.
+  ///
+  /// The values are validated first and the request headers are then replaced in a single
+  /// write, so either every modified pseudo-header is applied or none is.
+  pub fn commit(&mut self) -> WasmResult {
+    let mut modified = Vec::new();
+    if self.method_modified {
+      modified.push((":method", self.method.as_str().to_string()));
+    }
+    if self.scheme_modified {
+      modified.push((":scheme", self.scheme.clone()));
+    }
+    if self.authority_modified {
+      modified.push((":authority", self.authority.clone()));
+    }
+    if self.path_modified {
+      modified.push((":path", self.path_and_query()));
+    }
+    if modified.is_empty() {
+      return WasmResult::Ok;
+    }
+    if let Some((key, value)) = modified.iter().find(|(_, value)| {
+      value.is_empty() || value.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0)
+    }) {
+      warn!("invalid value for {}: {:?}", key, value);
+      return WasmResult::BadArgument;
+    }
+    let mut pairs = match get_header_map_pair_list(HeaderMapType::RequestHeaders) {
+      Ok(pairs) => pairs,
+      Err(e) => {
+        warn!("failed to read request headers: {}", e);
+        return WasmResult::InternalFailure;
+      }
+    };
+    for (key, value) in modified {
+      match pairs.iter_mut().find(|(k, _)| k == key) {
+        Some(pair) => pair.1 = value,
+        None => pairs.insert(0, (key.to_string(), value)),
+      }
+    }
+    match set_header_map_pair_list(HeaderMapType::RequestHeaders, &pairs) {
+      WasmResult::Ok => {}
+      r => return r,
+    }
+    self.method_modified = false;
+    self.scheme_modified = false;
+    self.authority_modified = false;
+    self.path_modified = false;
+    clear_route_cache()
+  }
+}
@@ -0,0 +1,89 @@
+use crate::context::*;
+use crate::payload::*;
+use log::warn;
+use std::sync::Arc;
+
+/// Singleton service running in its own VM, e.g. as an `envoy.bootstrap.wasm` extension. It
+/// never sees HTTP streams and interacts with filters through shared queues.
+pub trait ServiceContext {
+  fn on_start(&self, _vm_configuration: Option<Vec<u8>>) -> bool {
+    true
+  }
+  fn on_configure(&self, _configuration: Option<Vec<u8>>) -> bool {
+    true
+  }
+  fn on_tick(&self) {}
+  fn on_queue_ready(&self, _queue_id: u32) {}
+}
+
+pub trait ServiceContextFactory {
+  fn create(&self) -> Arc<dyn ServiceContext + Sync + Send>;
+}
+
+impl<F> ServiceContextFactory for F
+where
+  F: Fn() -> Arc<dyn ServiceContext + Sync + Send>,
+{
+  fn create(&self) -> Arc<dyn ServiceContext + Sync + Send> {
+    self()
+  }
+}
+
+struct ServiceRootContext {
+  service: Arc<dyn ServiceContext + Sync + Send>,
+}
+
+pub(crate) fn read_configuration(
+  size: u32,
+  read: fn() -> Result<Vec<u8>, String>,
+) -> Option<Vec<u8>> {
+  if size == 0 {
+    return None;
+  }
+  match read() {
+    Ok(c) => Some(c),
+    Err(e) => {
+      warn!("failed to read configuration: {}", e);
+      None
+    }
+  }
+}
+
+impl RootContext for ServiceRootContext {
+  fn on_start(&self, _configuration_size: u32) -> u32 {
+    let configuration = read_configuration(_configuration_size, get_vm_configuration);
+    self.service.on_start(configuration) as u32
+  }
+
+  fn on_configure(&self, _configuration_size: u32) -> bool {
+    let configuration = read_configuration(_configuration_size, get_plugin_configuration);
+    self.service.on_configure(configuration)
+  }
+
+  fn on_tick(&self) {
+    self.service.on_tick()
+  }
+
+  fn on_queue_ready(&self, _queue_id: u32) {
+    self.service.on_queue_ready(_queue_id)
+  }
+}
+
+/// Registers a service under `root_id`. Stream contexts created for this root id, if any,
+/// pass traffic through.
+pub fn register_service_factory<S>(root_id: &str, factory: S)
+where
+  S: ServiceContextFactory + Sync + Send + 'static,
+{
+  register_factory(
+    root_id,
+    |_root_context: Arc<dyn RootContext + Sync + Send>| -> Arc<dyn Context + Sync + Send> {
+      Arc::new(NoopContext {})
+    },
+    move || -> Arc<dyn RootContext + Sync + Send> {
+      Arc::new(ServiceRootContext {
+        service: factory.create(),
+      })
+    },
+  );
+}
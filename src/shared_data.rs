@@ -0,0 +1,69 @@
+use crate::host::*;
+use crate::types::*;
+use std::os::raw::c_char;
+use std::ptr::null_mut;
+
+/// Reads `key` from the data shared by the VMs of the same vm_id, along with its CAS. The value
+/// is `None` if the key was never set, in which case the CAS is 0.
+pub fn get_shared_data(key: &str) -> Result<(Option<Vec<u8>>, u32), String> {
+  let data_ptr: *mut c_char = null_mut::<c_char>();
+  let mut size: usize = 0;
+  let mut cas: u32 = 0;
+  unsafe {
+    match to_wasm_result(proxy_get_shared_data(
+      key.as_ptr() as *const c_char,
+      key.len(),
+      &data_ptr,
+      &mut size,
+      &mut cas,
+    )) {
+      WasmResult::Ok => {
+        if data_ptr.is_null() || size == 0 {
+          Ok((Some(Vec::new()), cas))
+        } else {
+          Ok((
+            Some(Vec::from_raw_parts(data_ptr as *mut u8, size, size)),
+            cas,
+          ))
+        }
+      }
+      WasmResult::NotFound => Ok((None, 0)),
+      r => Err(r.to_string()),
+    }
+  }
+}
+
+/// Stores `value` under `key`. Unless `cas` is 0, the write fails with `CasMismatch` when the
+/// value was changed since `cas` was read.
+pub fn set_shared_data(key: &str, value: &[u8], cas: u32) -> WasmResult {
+  unsafe {
+    to_wasm_result(proxy_set_shared_data(
+      key.as_ptr() as *const c_char,
+      key.len(),
+      value.as_ptr() as *const c_char,
+      value.len(),
+      cas,
+    ))
+  }
+}
+
+const MAX_CAS_ATTEMPTS: usize = 8;
+
+/// Replaces the value of `key` with the one computed by `f` from the current value, retrying
+/// when another VM updates the key concurrently. `f` returns the new value along with a result
+/// passed to the caller.
+pub fn update_shared_data<T, F>(key: &str, mut f: F) -> Result<T, String>
+where
+  F: FnMut(Option<&[u8]>) -> (Vec<u8>, T),
+{
+  for _ in 0..MAX_CAS_ATTEMPTS {
+    let (current, cas) = get_shared_data(key)?;
+    let (value, result) = f(current.as_deref());
+    match set_shared_data(key, &value, cas) {
+      WasmResult::Ok => return Ok(result),
+      WasmResult::CasMismatch => continue,
+      r => return Err(r.to_string()),
+    }
+  }
+  Err(format!("too many concurrent updates of {}", key))
+}
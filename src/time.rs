@@ -0,0 +1,139 @@
+use crate::host::*;
+use std::convert::TryFrom;
+use std::ops::{Add, Sub};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Latest time returned by `Instant::now`, so that instants never go backwards.
+static LAST_INSTANT_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Wall clock time of the host in nanoseconds since the Unix epoch.
+pub fn current_time_nanos() -> u64 {
+  let mut nanos: u64 = 0;
+  unsafe {
+    proxy_get_current_time_nanoseconds(&mut nanos);
+  }
+  nanos
+}
+
+/// Wall clock time of the host. `SystemTime::now()` is not available on
+/// `wasm32-unknown-unknown`.
+pub fn current_time() -> SystemTime {
+  UNIX_EPOCH + Duration::from_nanos(current_time_nanos())
+}
+
+/// Point in time for measuring durations, such as the latency between `on_request_headers` and
+/// `on_response_headers`. The host only exposes a wall clock, which is clamped so that instants
+/// are monotonic within the VM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+  nanos: u64,
+}
+
+impl Instant {
+  pub fn now() -> Instant {
+    let now = current_time_nanos();
+    let last = LAST_INSTANT_NANOS.fetch_max(now, Ordering::Relaxed);
+    Instant {
+      nanos: std::cmp::max(now, last),
+    }
+  }
+
+  /// Returns zero if `earlier` is later than this instant.
+  pub fn duration_since(&self, earlier: Instant) -> Duration {
+    Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+  }
+
+  pub fn elapsed(&self) -> Duration {
+    Instant::now().duration_since(*self)
+  }
+
+  pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+    let nanos = u64::try_from(duration.as_nanos()).ok()?;
+    self.nanos.checked_add(nanos).map(|nanos| Instant { nanos })
+  }
+
+  pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+    let nanos = u64::try_from(duration.as_nanos()).ok()?;
+    self.nanos.checked_sub(nanos).map(|nanos| Instant { nanos })
+  }
+
+  /// Wall clock time the instant was taken at.
+  pub fn to_system_time(&self) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(self.nanos)
+  }
+}
+
+impl Add<Duration> for Instant {
+  type Output = Instant;
+  fn add(self, duration: Duration) -> Instant {
+    self
+      .checked_add(duration)
+      .expect("overflow when adding duration to instant")
+  }
+}
+
+impl Sub<Duration> for Instant {
+  type Output = Instant;
+  fn sub(self, duration: Duration) -> Instant {
+    self
+      .checked_sub(duration)
+      .expect("overflow when subtracting duration from instant")
+  }
+}
+
+impl Sub<Instant> for Instant {
+  type Output = Duration;
+  fn sub(self, earlier: Instant) -> Duration {
+    self.duration_since(earlier)
+  }
+}
+
+/// Formats `time` as RFC 3339 in UTC with millisecond precision, e.g.
+/// `2020-11-05T10:20:30.123Z`.
+pub fn format_rfc3339(time: SystemTime) -> String {
+  let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+  let seconds = since_epoch.as_secs();
+  let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+  let seconds_of_day = seconds % 86_400;
+  format!(
+    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+    year,
+    month,
+    day,
+    seconds_of_day / 3600,
+    seconds_of_day / 60 % 60,
+    seconds_of_day % 60,
+    since_epoch.subsec_millis()
+  )
+}
+
+// Converts days since the Unix epoch to a proleptic Gregorian date, see
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+  let z = days + 719_468;
+  let era = z.div_euclid(146_097);
+  let day_of_era = z.rem_euclid(146_097);
+  let year_of_era =
+    (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let mp = (5 * day_of_year + 2) / 153;
+  let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+  let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+  (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn formats_rfc3339() {
+    assert_eq!(format_rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    let leap_day = UNIX_EPOCH + Duration::from_millis(1_582_978_230_123);
+    assert_eq!(format_rfc3339(leap_day), "2020-02-29T12:10:30.123Z");
+    let end_of_century = UNIX_EPOCH + Duration::from_secs(4_102_444_799);
+    assert_eq!(format_rfc3339(end_of_century), "2099-12-31T23:59:59.000Z");
+  }
+}
@@ -0,0 +1,406 @@
+use crate::context::{active_context_id, is_root_context};
+use crate::extensions::extensions;
+use crate::payload::get_request_header;
+use crate::payload_wrapper::add_header_map_value;
+use crate::time::current_time_nanos;
+use crate::types::*;
+use log::warn;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// ====================== Id Generation API =============================
+static RNG_STATE: AtomicU64 = AtomicU64::new(0);
+const SPLITMIX_INCREMENT: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Pseudo-random number from a splitmix64 sequence seeded with the host time. It needs neither
+/// WASI nor a host entropy source, and is not suitable for cryptography.
+pub fn random_u64() -> u64 {
+  if RNG_STATE.load(Ordering::Relaxed) == 0 {
+    let _ = RNG_STATE.compare_exchange(
+      0,
+      current_time_nanos() | 1,
+      Ordering::Relaxed,
+      Ordering::Relaxed,
+    );
+  }
+  let mut z = RNG_STATE
+    .fetch_add(SPLITMIX_INCREMENT, Ordering::Relaxed)
+    .wrapping_add(SPLITMIX_INCREMENT);
+  z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+  z ^ (z >> 31)
+}
+
+/// Non-zero span id.
+pub fn new_span_id() -> u64 {
+  loop {
+    let id = random_u64();
+    if id != 0 {
+      return id;
+    }
+  }
+}
+
+/// Trace id made of the host time in seconds followed by 96 random bits.
+pub fn new_trace_id() -> u128 {
+  let seconds = (current_time_nanos() / 1_000_000_000) as u32;
+  let random = ((random_u64() as u128) << 32) | (random_u64() >> 32) as u128;
+  ((seconds as u128) << 96) | random | 1
+}
+// ====================== Id Generation API =============================
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Propagation {
+  /// `traceparent` and `tracestate`.
+  W3c,
+  /// `b3`
+  B3Single,
+  /// `x-b3-traceid`, `x-b3-spanid`, `x-b3-parentspanid` and `x-b3-sampled`.
+  B3Multi,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceContext {
+  pub trace_id: u128,
+  pub span_id: u64,
+  pub parent_span_id: Option<u64>,
+  /// Sampling decision, if one was made upstream.
+  pub sampled: Option<bool>,
+  pub trace_state: Option<String>,
+  /// Format the context was read from, used when it is propagated.
+  pub propagation: Propagation,
+}
+
+fn parse_hex_u64(value: &str) -> Option<u64> {
+  match value.len() {
+    16 if value.bytes().all(|b| b.is_ascii_hexdigit()) => u64::from_str_radix(value, 16).ok(),
+    _ => None,
+  }
+}
+
+// Accepts 64-bit B3 trace ids as well.
+fn parse_trace_id(value: &str) -> Option<u128> {
+  let valid =
+    (value.len() == 32 || value.len() == 16) && value.bytes().all(|b| b.is_ascii_hexdigit());
+  match valid {
+    true => u128::from_str_radix(value, 16).ok().filter(|id| *id != 0),
+    false => None,
+  }
+}
+
+fn request_header(key: &str) -> Option<String> {
+  match get_request_header(key.to_string()).map(|v| v.to_string()) {
+    Ok(v) if !v.is_empty() => Some(v),
+    _ => None,
+  }
+}
+
+impl TraceContext {
+  /// Starts a new trace.
+  pub fn new_root(propagation: Propagation) -> TraceContext {
+    TraceContext {
+      trace_id: new_trace_id(),
+      span_id: new_span_id(),
+      parent_span_id: None,
+      sampled: None,
+      trace_state: None,
+      propagation,
+    }
+  }
+
+  /// Context of a span started as a child of this one.
+  pub fn child(&self) -> TraceContext {
+    TraceContext {
+      span_id: new_span_id(),
+      parent_span_id: Some(self.span_id),
+      ..self.clone()
+    }
+  }
+
+  /// Parses a version 00 `traceparent` header.
+  pub fn from_traceparent(traceparent: &str, tracestate: Option<&str>) -> Option<TraceContext> {
+    let parts: Vec<&str> = traceparent.trim().split('-').collect();
+    if parts.len() < 4 || parts[0].len() != 2 || parts[0] == "ff" || parts[3].len() != 2 {
+      return None;
+    }
+    if parts[0] == "00" && parts.len() != 4 {
+      return None;
+    }
+    if parts[1].len() != 32 {
+      return None;
+    }
+    let trace_id = parse_trace_id(parts[1])?;
+    let span_id = parse_hex_u64(parts[2]).filter(|id| *id != 0)?;
+    let flags = u8::from_str_radix(parts[3], 16).ok()?;
+    Some(TraceContext {
+      trace_id,
+      span_id,
+      parent_span_id: None,
+      sampled: Some(flags & 1 == 1),
+      trace_state: tracestate
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty()),
+      propagation: Propagation::W3c,
+    })
+  }
+
+  /// Parses a `b3` header: `{trace_id}-{span_id}[-{sampling}[-{parent_span_id}]]`. A header
+  /// carrying only a sampling decision has no context.
+  pub fn from_b3(b3: &str) -> Option<TraceContext> {
+    let parts: Vec<&str> = b3.trim().split('-').collect();
+    if parts.len() < 2 || parts.len() > 4 {
+      return None;
+    }
+    let sampled = match parts.get(2) {
+      None => None,
+      Some(&"1") | Some(&"d") => Some(true),
+      Some(&"0") => Some(false),
+      Some(_) => return None,
+    };
+    let parent_span_id = match parts.get(3) {
+      Some(p) => Some(parse_hex_u64(p)?),
+      None => None,
+    };
+    Some(TraceContext {
+      trace_id: parse_trace_id(parts[0])?,
+      span_id: parse_hex_u64(parts[1])?,
+      parent_span_id,
+      sampled,
+      trace_state: None,
+      propagation: Propagation::B3Single,
+    })
+  }
+
+  /// Builds a context from `x-b3-*` header values.
+  pub fn from_b3_headers(
+    trace_id: &str,
+    span_id: &str,
+    parent_span_id: Option<&str>,
+    sampled: Option<&str>,
+    flags: Option<&str>,
+  ) -> Option<TraceContext> {
+    let sampled = match (flags, sampled) {
+      (Some("1"), _) => Some(true),
+      (_, Some("1")) | (_, Some("true")) => Some(true),
+      (_, Some("0")) | (_, Some("false")) => Some(false),
+      _ => None,
+    };
+    let parent_span_id = match parent_span_id {
+      Some(p) => Some(parse_hex_u64(p)?),
+      None => None,
+    };
+    Some(TraceContext {
+      trace_id: parse_trace_id(trace_id)?,
+      span_id: parse_hex_u64(span_id)?,
+      parent_span_id,
+      sampled,
+      trace_state: None,
+      propagation: Propagation::B3Multi,
+    })
+  }
+
+  /// Reads the context of the current request, trying W3C, single header B3 and multi header B3
+  /// in this order.
+  pub fn from_request() -> Option<TraceContext> {
+    if let Some(traceparent) = request_header("traceparent") {
+      let tracestate = request_header("tracestate");
+      if let Some(context) = TraceContext::from_traceparent(&traceparent, tracestate.as_deref()) {
+        return Some(context);
+      }
+    }
+    if let Some(context) = request_header("b3").and_then(|b3| TraceContext::from_b3(&b3)) {
+      return Some(context);
+    }
+    let trace_id = request_header("x-b3-traceid")?;
+    let span_id = request_header("x-b3-spanid")?;
+    TraceContext::from_b3_headers(
+      &trace_id,
+      &span_id,
+      request_header("x-b3-parentspanid").as_deref(),
+      request_header("x-b3-sampled").as_deref(),
+      request_header("x-b3-flags").as_deref(),
+    )
+  }
+
+  pub fn traceparent(&self) -> String {
+    format!(
+      "00-{:032x}-{:016x}-{:02x}",
+      self.trace_id,
+      self.span_id,
+      self.sampled.unwrap_or(false) as u8
+    )
+  }
+
+  pub fn b3(&self) -> String {
+    let mut b3 = format!("{:032x}-{:016x}", self.trace_id, self.span_id);
+    if let Some(sampled) = self.sampled {
+      b3.push_str(if sampled { "-1" } else { "-0" });
+      if let Some(parent_span_id) = self.parent_span_id {
+        b3.push_str(&format!("-{:016x}", parent_span_id));
+      }
+    }
+    b3
+  }
+
+  /// Headers carrying this context in the given format.
+  pub fn headers(&self, propagation: Propagation) -> Vec<(String, String)> {
+    let mut headers = Vec::new();
+    match propagation {
+      Propagation::W3c => {
+        headers.push(("traceparent".to_string(), self.traceparent()));
+        if let Some(trace_state) = &self.trace_state {
+          headers.push(("tracestate".to_string(), trace_state.clone()));
+        }
+      }
+      Propagation::B3Single => headers.push(("b3".to_string(), self.b3())),
+      Propagation::B3Multi => {
+        headers.push((
+          "x-b3-traceid".to_string(),
+          format!("{:032x}", self.trace_id),
+        ));
+        headers.push(("x-b3-spanid".to_string(), format!("{:016x}", self.span_id)));
+        if let Some(parent_span_id) = self.parent_span_id {
+          headers.push((
+            "x-b3-parentspanid".to_string(),
+            format!("{:016x}", parent_span_id),
+          ));
+        }
+        if let Some(sampled) = self.sampled {
+          headers.push(("x-b3-sampled".to_string(), (sampled as u8).to_string()));
+        }
+      }
+    }
+    headers
+  }
+}
+
+// ====================== Callout Propagation API =============================
+static CALLOUT_PROPAGATION: AtomicBool = AtomicBool::new(true);
+
+/// Whether callouts from stream contexts carry a child of the trace context of the request.
+/// Enabled by default; requests without trace context are not affected.
+pub fn set_callout_propagation(enabled: bool) {
+  CALLOUT_PROPAGATION.store(enabled, Ordering::Relaxed);
+}
+
+/// Trace context of the current request. It is read from the request headers once and kept in
+/// the extensions of the context, where it can be replaced with `insert_extension`.
+pub fn trace_context() -> Option<TraceContext> {
+  // Callouts may be dispatched while the extensions are borrowed, see `with_extension`.
+  let extensions = extensions();
+  let mut extensions = match extensions.try_lock() {
+    Ok(extensions) => extensions,
+    Err(_) => return TraceContext::from_request(),
+  };
+  if let Some(context) = extensions.get::<TraceContext>() {
+    return Some(context.clone());
+  }
+  let context = TraceContext::from_request()?;
+  extensions.insert(context.clone());
+  Some(context)
+}
+
+fn callout_trace_headers() -> Vec<(String, String)> {
+  if !CALLOUT_PROPAGATION.load(Ordering::Relaxed) || is_root_context(active_context_id()) {
+    return Vec::new();
+  }
+  match trace_context() {
+    Some(context) => {
+      let child = context.child();
+      child.headers(child.propagation)
+    }
+    None => Vec::new(),
+  }
+}
+
+/// Appends the trace headers of a callout to `headers`, unless they were set by the caller.
+pub(crate) fn inject_callout_headers(headers: &mut Vec<(String, String)>) {
+  for (key, value) in callout_trace_headers() {
+    if !headers.iter().any(|(k, _)| k.eq_ignore_ascii_case(&key)) {
+      headers.push((key, value));
+    }
+  }
+}
+
+/// Adds the trace headers to the initial metadata of a gRPC callout being created.
+pub(crate) fn inject_grpc_initial_metadata() {
+  for (key, value) in callout_trace_headers() {
+    match add_header_map_value(HeaderMapType::GrpcCreateInitialMetadata, key, value) {
+      WasmResult::Ok => {}
+      r => warn!("failed to add grpc initial metadata: {}", r),
+    }
+  }
+}
+// ====================== Callout Propagation API =============================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+  #[test]
+  fn parses_and_formats_traceparent() {
+    let context = TraceContext::from_traceparent(TRACEPARENT, Some(" vendor=value ")).unwrap();
+    assert_eq!(context.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+    assert_eq!(context.span_id, 0x00f067aa0ba902b7);
+    assert_eq!(context.sampled, Some(true));
+    assert_eq!(context.trace_state.as_deref(), Some("vendor=value"));
+    assert_eq!(context.traceparent(), TRACEPARENT);
+  }
+
+  #[test]
+  fn rejects_invalid_traceparent() {
+    let invalid = [
+      "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+      "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+      "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+      "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+      "00-4bf92f3577b34da6-00f067aa0ba902b7-01",
+      "00-4bf92f3577b34da6a3ce929d0e0e473g-00f067aa0ba902b7-01",
+    ];
+    for traceparent in &invalid {
+      assert_eq!(TraceContext::from_traceparent(traceparent, None), None);
+    }
+    // Later versions may append fields.
+    assert!(TraceContext::from_traceparent(
+      "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-extra",
+      None
+    )
+    .is_some());
+  }
+
+  #[test]
+  fn parses_and_formats_b3() {
+    let b3 = "80f198ee56343ba864fe8b2a57d3eff7-e457b5a2e4d86bd1-1-05e3ac9a4f6e3b90";
+    let context = TraceContext::from_b3(b3).unwrap();
+    assert_eq!(context.parent_span_id, Some(0x05e3ac9a4f6e3b90));
+    assert_eq!(context.sampled, Some(true));
+    assert_eq!(context.b3(), b3);
+    let short = TraceContext::from_b3("a3ce929d0e0e4736-00f067aa0ba902b7").unwrap();
+    assert_eq!(short.trace_id, 0xa3ce929d0e0e4736);
+    assert_eq!(short.sampled, None);
+    assert_eq!(TraceContext::from_b3("0"), None);
+    assert_eq!(
+      TraceContext::from_b3("a3ce929d0e0e4736-00f067aa0ba902b7-x"),
+      None
+    );
+  }
+
+  #[test]
+  fn parses_multi_header_b3() {
+    let context = TraceContext::from_b3_headers(
+      "a3ce929d0e0e4736",
+      "00f067aa0ba902b7",
+      None,
+      Some("0"),
+      Some("1"),
+    )
+    .unwrap();
+    assert_eq!(context.sampled, Some(true));
+    assert_eq!(context.propagation, Propagation::B3Multi);
+    assert_eq!(
+      TraceContext::from_b3_headers("a3ce929d0e0e4736", "bad", None, None, None),
+      None
+    );
+  }
+}
@@ -48,6 +48,8 @@ pub enum BufferType {
   NetworkUpstreamData,   // During the onLog callback these are immutable
   HttpCallResponseBody,  // Immutable
   GrpcReceiveBuffer,     // Immutable
+  VmConfiguration,       // Immutable
+  PluginConfiguration,   // Immutable
   MAX,
 }
 
@@ -81,6 +83,7 @@ pub enum FilterDataStatus {
   StopIterationNoBuffer,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum GrpcStatus {
   Ok,
   Canceled,
@@ -103,6 +106,16 @@ pub enum GrpcStatus {
   InvalidCode,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum LogLevel {
+  Trace,
+  Debug,
+  Info,
+  Warn,
+  Error,
+  Critical,
+}
+
 pub enum MetricType {
   Counter,
   Gauge,
@@ -136,6 +149,17 @@ impl TryFrom<u32> for WasmResult {
   }
 }
 
+/// Converts a raw hostcall status, mapping unknown codes to `InternalFailure`.
+pub fn to_wasm_result(code: u32) -> WasmResult {
+  match WasmResult::try_from(code) {
+    Ok(r) => r,
+    Err(e) => {
+      log::warn!("failed to convert: {}", e);
+      WasmResult::InternalFailure
+    }
+  }
+}
+
 impl std::fmt::Display for WasmResult {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match *self {
@@ -156,6 +180,83 @@ impl std::fmt::Display for WasmResult {
   }
 }
 
+impl TryFrom<u32> for LogLevel {
+  type Error = String;
+  fn try_from(n: u32) -> Result<Self, String> {
+    match n {
+      0 => Ok(LogLevel::Trace),
+      1 => Ok(LogLevel::Debug),
+      2 => Ok(LogLevel::Info),
+      3 => Ok(LogLevel::Warn),
+      4 => Ok(LogLevel::Error),
+      5 => Ok(LogLevel::Critical),
+      _ => Err(format!("invalid log level: {}", n)),
+    }
+  }
+}
+
+impl TryFrom<u32> for GrpcStatus {
+  type Error = String;
+  fn try_from(n: u32) -> Result<Self, Self::Error> {
+    match n {
+      0 => Ok(GrpcStatus::Ok),
+      1 => Ok(GrpcStatus::Canceled),
+      2 => Ok(GrpcStatus::Unknown),
+      3 => Ok(GrpcStatus::InvalidArgument),
+      4 => Ok(GrpcStatus::DeadlineExceeded),
+      5 => Ok(GrpcStatus::NotFound),
+      6 => Ok(GrpcStatus::AlreadyExists),
+      7 => Ok(GrpcStatus::PermissionDenied),
+      8 => Ok(GrpcStatus::ResourceExhausted),
+      9 => Ok(GrpcStatus::FailedPrecondition),
+      10 => Ok(GrpcStatus::Aborted),
+      11 => Ok(GrpcStatus::OutOfRange),
+      12 => Ok(GrpcStatus::Unimplemented),
+      13 => Ok(GrpcStatus::Internal),
+      14 => Ok(GrpcStatus::Unavailable),
+      15 => Ok(GrpcStatus::DataLoss),
+      16 => Ok(GrpcStatus::Unauthenticated),
+      _ => Err(format!("invalid grpc status: {}", n)),
+    }
+  }
+}
+
+impl std::fmt::Display for GrpcStatus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match *self {
+      GrpcStatus::Ok => write!(f, "OK"),
+      GrpcStatus::Canceled => write!(f, "CANCELLED"),
+      GrpcStatus::Unknown => write!(f, "UNKNOWN"),
+      GrpcStatus::InvalidArgument => write!(f, "INVALID_ARGUMENT"),
+      GrpcStatus::DeadlineExceeded => write!(f, "DEADLINE_EXCEEDED"),
+      GrpcStatus::NotFound => write!(f, "NOT_FOUND"),
+      GrpcStatus::AlreadyExists => write!(f, "ALREADY_EXISTS"),
+      GrpcStatus::PermissionDenied => write!(f, "PERMISSION_DENIED"),
+      GrpcStatus::ResourceExhausted => write!(f, "RESOURCE_EXHAUSTED"),
+      GrpcStatus::FailedPrecondition => write!(f, "FAILED_PRECONDITION"),
+      GrpcStatus::Aborted => write!(f, "ABORTED"),
+      GrpcStatus::OutOfRange => write!(f, "OUT_OF_RANGE"),
+      GrpcStatus::Unimplemented => write!(f, "UNIMPLEMENTED"),
+      GrpcStatus::Internal => write!(f, "INTERNAL"),
+      GrpcStatus::Unavailable => write!(f, "UNAVAILABLE"),
+      GrpcStatus::DataLoss => write!(f, "DATA_LOSS"),
+      GrpcStatus::Unauthenticated => write!(f, "UNAUTHENTICATED"),
+      GrpcStatus::MaximumValid => write!(f, "unimplemented"),
+      GrpcStatus::InvalidCode => write!(f, "unimplemented"),
+    }
+  }
+}
+
+impl From<GrpcStatus> for u32 {
+  fn from(status: GrpcStatus) -> u32 {
+    grpc_status_to_int(status)
+  }
+}
+
+pub fn metric_type_to_int(mtype: MetricType) -> u32 {
+  mtype as u32
+}
+
 pub fn filter_trailer_status_to_int(status: FilterTrailersStatus) -> u32 {
   status as u32
 }
@@ -180,6 +281,14 @@ pub fn header_map_type_to_int(htype: HeaderMapType) -> u32 {
   htype as u32
 }
 
+pub fn buffer_type_to_int(btype: BufferType) -> u32 {
+  btype as u32
+}
+
+pub fn log_level_to_int(level: LogLevel) -> u32 {
+  level as u32
+}
+
 pub fn grpc_status_to_int(status: GrpcStatus) -> u32 {
   status as u32
 }